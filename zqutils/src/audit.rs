@@ -0,0 +1,266 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use crate::{redact, utils};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The log every CommandBuilder without a log of its own writes to, if any.
+static GLOBAL_LOG: RwLock<Option<Arc<AuditLog>>> = RwLock::new(None);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Spawn,
+    Complete,
+}
+
+/// One line of an audit log. A command gets a `Spawn` entry when it starts and, if it
+/// was run (rather than just spawned) through zqutils, a `Complete` entry with the same
+/// `id` when it finishes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub event: AuditEvent,
+    pub pid: Option<u32>,
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub cwd: Option<String>,
+    /// Environment overrides only - not the whole inherited environment.
+    pub env: BTreeMap<String, String>,
+    /// Milliseconds since the epoch.
+    pub started_at_ms: u64,
+    pub duration_ms: Option<u64>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn command_line(&self) -> String {
+        let mut result = self.cmd.clone();
+        for arg in &self.args {
+            result.push(' ');
+            result.push_str(arg);
+        }
+        result
+    }
+
+    pub fn outcome(&self) -> String {
+        match (self.timed_out, self.signal, self.exit_code) {
+            (true, _, _) => "timed out".to_string(),
+            (_, Some(sig), _) => format!("signal {sig}"),
+            (_, _, Some(code)) => format!("exit {code}"),
+            _ => "running".to_string(),
+        }
+    }
+}
+
+/// A JSON lines file we append an entry to for every command spawned and completed.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+    /// How much of stdout and stderr to keep, in bytes. We keep the end.
+    max_output: usize,
+    next_id: AtomicU64,
+}
+
+impl AuditLog {
+    /// Open (appending to) the log at `path`.
+    pub fn open(path: &Path) -> Result<Arc<Self>> {
+        Self::open_with_max_output(path, 4096)
+    }
+
+    pub fn open_with_max_output(path: &Path, max_output: usize) -> Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Arc::new(Self {
+            file: Mutex::new(file),
+            max_output,
+            next_id: AtomicU64::new(1),
+        }))
+    }
+
+    /// Log every command that doesn't have a log of its own here.
+    pub fn install_global(log: Arc<AuditLog>) {
+        if let Ok(mut global) = GLOBAL_LOG.write() {
+            *global = Some(log);
+        }
+    }
+
+    pub fn remove_global() {
+        if let Ok(mut global) = GLOBAL_LOG.write() {
+            *global = None;
+        }
+    }
+
+    pub fn global() -> Option<Arc<AuditLog>> {
+        GLOBAL_LOG.read().ok().and_then(|x| x.clone())
+    }
+
+    pub fn write(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("Audit log lock is poisoned"))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Record that `cmd` was just spawned as `pid`.
+    pub(crate) fn record_spawn(
+        self: &Arc<Self>,
+        cmd: &CommandBuilder,
+        pid: Option<u32>,
+    ) -> PendingAudit {
+        let env: HashMap<String, String> = cmd.redacted_env();
+        let entry = AuditEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            event: AuditEvent::Spawn,
+            pid,
+            cmd: cmd.get_cmd().unwrap_or_default().to_string(),
+            args: cmd.redacted_args(),
+            cwd: cmd.get_cwd().map(|x| x.to_string()),
+            env: env.into_iter().collect(),
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| u64::try_from(x.as_millis()).unwrap_or(u64::MAX))
+                .unwrap_or(0),
+            duration_ms: None,
+            exit_code: None,
+            signal: None,
+            timed_out: false,
+            stdout: None,
+            stderr: None,
+        };
+        if let Err(e) = self.write(&entry) {
+            println!("⚠️ Cannot write audit log - {e}");
+        }
+        PendingAudit {
+            log: self.clone(),
+            entry,
+            started: Instant::now(),
+            secrets: cmd.secrets(),
+        }
+    }
+
+    fn truncate(&self, output: &[u8]) -> String {
+        if output.len() <= self.max_output {
+            utils::string_or_empty_from_u8(output)
+        } else {
+            let tail = &output[output.len() - self.max_output..];
+            format!(
+                "[... {0} bytes truncated]{1}",
+                output.len() - self.max_output,
+                String::from_utf8_lossy(tail)
+            )
+        }
+    }
+}
+
+/// A spawned command whose completion we have yet to record.
+#[derive(Debug)]
+pub(crate) struct PendingAudit {
+    log: Arc<AuditLog>,
+    entry: AuditEntry,
+    started: Instant,
+    /// Secrets to mask in the command's output.
+    secrets: Vec<String>,
+}
+
+impl PendingAudit {
+    pub(crate) fn complete(mut self, output: &CommandOutput) {
+        self.entry.event = AuditEvent::Complete;
+        self.entry.duration_ms =
+            Some(u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX));
+        self.entry.exit_code = output.signal.is_none().then_some(output.status_code);
+        self.entry.signal = output.signal;
+        self.entry.timed_out = output.timed_out;
+        self.entry.stdout = Some(redact::redact_with(
+            &self.log.truncate(&output.stdout),
+            &self.secrets,
+        ));
+        self.entry.stderr = Some(redact::redact_with(
+            &self.log.truncate(&output.stderr),
+            &self.secrets,
+        ));
+        if let Err(e) = self.log.write(&self.entry) {
+            println!("⚠️ Cannot write audit log - {e}");
+        }
+    }
+}
+
+/// An audit log, read back in.
+#[derive(Debug, Clone)]
+pub struct AuditReader {
+    pub entries: Vec<AuditEntry>,
+}
+
+impl AuditReader {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut entries = Vec::new();
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| {
+                anyhow!(
+                    "Cannot parse audit log {0} line {1} - {e}",
+                    path.display(),
+                    idx + 1
+                )
+            })?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// The commands which finished, in the order they finished.
+    pub fn completed(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.entries
+            .iter()
+            .filter(|x| x.event == AuditEvent::Complete)
+    }
+
+    /// One line per completed command: outcome, duration and command line.
+    pub fn summary(&self) -> Vec<String> {
+        self.completed()
+            .map(|x| {
+                format!(
+                    "{0:<12} {1:>8}ms  {2}",
+                    x.outcome(),
+                    x.duration_ms.unwrap_or_default(),
+                    x.command_line()
+                )
+            })
+            .collect()
+    }
+
+    /// Completed commands (with their outcomes) which are in this log but not `other`
+    /// ("-") or in `other` but not this log ("+").
+    pub fn diff(&self, other: &AuditReader) -> Vec<String> {
+        let key = |x: &AuditEntry| format!("{0}: {1}", x.command_line(), x.outcome());
+        let mut ours: Vec<String> = self.completed().map(key).collect();
+        let mut result = Vec::new();
+        for theirs in other.completed().map(key) {
+            if let Some(pos) = ours.iter().position(|x| *x == theirs) {
+                ours.remove(pos);
+            } else {
+                result.push(format!("+ {theirs}"));
+            }
+        }
+        let mut removed: Vec<String> = ours.into_iter().map(|x| format!("- {x}")).collect();
+        removed.append(&mut result);
+        removed
+    }
+}
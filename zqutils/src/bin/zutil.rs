@@ -6,7 +6,7 @@ use ethers::core::rand::thread_rng;
 use ethers::signers::{LocalWallet, Signer};
 use ethers::types::H160;
 use std::env;
-use zqutils::{bq, queries};
+use zqutils::{bq, queries, registry};
 
 #[derive(Parser, Debug)]
 #[clap(about)]
@@ -111,6 +111,7 @@ impl Cli {
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse_from(env::args());
+    registry::forward_signals(true);
 
     match &cli.command {
         Commands::Addresses(s) => cli.addresses_for_privkey(&s.privkey).await?,
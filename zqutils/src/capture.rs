@@ -0,0 +1,218 @@
+use crate::watchers::OutputStream;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt as _, BufWriter};
+
+/// Distinguishes the spill files of one process.
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(1);
+
+/// How much of a command's output to keep in memory, per stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureLimit {
+    /// Keep at most this many bytes; once we have more, keep the head and the tail.
+    pub max_bytes: usize,
+    /// How many of those bytes come from the start of the output; the rest are the end.
+    pub head_bytes: usize,
+    /// Once we go over max_bytes, write all the output to a temporary file as well. The
+    /// file is left for the caller to delete.
+    pub spill_to_disk: bool,
+}
+
+impl CaptureLimit {
+    /// Keep `max_bytes`, half from the start and half from the end, and spill the rest.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            head_bytes: max_bytes / 2,
+            spill_to_disk: true,
+        }
+    }
+}
+
+/// What we captured of one stream.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Captured {
+    pub data: Vec<u8>,
+    /// Did we drop any of the output?
+    pub truncated: bool,
+    /// Where all of it went, if we spilled it.
+    pub spill: Option<PathBuf>,
+}
+
+impl From<Vec<u8>> for Captured {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ..Default::default()
+        }
+    }
+}
+
+/// Accumulates one stream's output within a CaptureLimit.
+pub(crate) struct Capture {
+    limit: Option<CaptureLimit>,
+    stream: OutputStream,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    total: usize,
+    spill: Option<(PathBuf, BufWriter<File>)>,
+    /// If we couldn't spill, don't keep trying.
+    spill_failed: bool,
+}
+
+impl Capture {
+    pub(crate) fn new(limit: Option<CaptureLimit>, stream: OutputStream) -> Self {
+        Self {
+            limit,
+            stream,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            total: 0,
+            spill: None,
+            spill_failed: false,
+        }
+    }
+
+    pub(crate) async fn push(&mut self, data: &[u8]) {
+        let limit = match self.limit {
+            None => {
+                self.head.extend_from_slice(data);
+                return;
+            }
+            Some(limit) => limit,
+        };
+        self.total += data.len();
+        if self.total > limit.max_bytes && limit.spill_to_disk {
+            self.spill(data).await;
+        }
+        let head_bytes = limit.head_bytes.min(limit.max_bytes);
+        let to_head = head_bytes.saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..to_head]);
+        let tail_bytes = limit.max_bytes - head_bytes;
+        self.tail.extend(&data[to_head..]);
+        if self.tail.len() > tail_bytes {
+            self.tail.drain(..self.tail.len() - tail_bytes);
+        }
+    }
+
+    /// Write `data` to the spill file, creating it (with everything so far) if need be.
+    async fn spill(&mut self, data: &[u8]) {
+        if self.spill_failed {
+            return;
+        }
+        if let Err(e) = self.try_spill(data).await {
+            println!("⚠️ Cannot spill output to disk - {e}");
+            self.spill_failed = true;
+            self.spill = None;
+        }
+    }
+
+    async fn try_spill(&mut self, data: &[u8]) -> Result<()> {
+        if self.spill.is_none() {
+            let path = std::env::temp_dir().join(format!(
+                "zqutils-{0}-{1}-{2:?}-{3:016x}.log",
+                std::process::id(),
+                NEXT_SPILL.fetch_add(1, Ordering::Relaxed),
+                self.stream,
+                rand::random::<u64>()
+            ));
+            // Never follow a link someone else planted, and keep the (unredacted)
+            // output to ourselves.
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .await?;
+            let mut file = BufWriter::new(file);
+            // Until now, we have had everything in the head and tail.
+            file.write_all(&self.head).await?;
+            let (first, second) = self.tail.as_slices();
+            file.write_all(first).await?;
+            file.write_all(second).await?;
+            self.spill = Some((path, file));
+        }
+        if let Some((_, file)) = &mut self.spill {
+            file.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn finish(mut self) -> Captured {
+        let kept = self.head.len() + self.tail.len();
+        let truncated = self.limit.is_some() && self.total > kept;
+        if let Some((_, file)) = &mut self.spill {
+            if let Err(e) = file.flush().await {
+                println!("⚠️ Cannot spill output to disk - {e}");
+                self.spill = None;
+            }
+        }
+        let mut data = self.head;
+        if truncated {
+            data.extend_from_slice(
+                format!("\n[... {0} bytes truncated ...]\n", self.total - kept).as_bytes(),
+            );
+        }
+        data.extend(self.tail);
+        Captured {
+            data,
+            truncated,
+            spill: self.spill.map(|(path, _)| path),
+        }
+    }
+}
+
+/// A line of output, and when it arrived.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutputEvent {
+    /// Since the command started.
+    pub elapsed: Duration,
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Every line a command printed on stdout and stderr, in the order we read them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// Milliseconds since the epoch.
+    pub started_at_ms: u64,
+    pub events: Vec<OutputEvent>,
+}
+
+impl Transcript {
+    pub(crate) fn new() -> Self {
+        Self {
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| u64::try_from(x.as_millis()).unwrap_or(u64::MAX))
+                .unwrap_or(0),
+            events: Vec::new(),
+        }
+    }
+
+    /// Both streams merged, one line per event: time since the start, then the line
+    /// prefixed as we echo it.
+    pub fn render(&self) -> String {
+        let mut result = String::new();
+        for event in &self.events {
+            let _ = writeln!(
+                result,
+                "[{0:>9.3}s] {1}{2}",
+                event.elapsed.as_secs_f64(),
+                event.stream.prefix(),
+                event.line
+            );
+        }
+        result
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
@@ -1,16 +1,413 @@
-use crate::utils;
+use crate::audit::{AuditLog, PendingAudit};
+use crate::capture::{Capture, CaptureLimit, Captured, Transcript};
+use crate::errors::CommandError;
+use crate::limits::{self, ResourceLimits, ResourceUsage};
+use crate::mode::ExecutionMode;
+use crate::pty::{self, PtySession};
+use crate::runner::CommandRunner;
+use crate::target::ExecutionTarget;
+use crate::watchers::{LineHub, LineWatchers, OutputStream};
+use crate::{process, redact, registry, utils};
 use anyhow::{anyhow, Result};
 use colored::{Color, Colorize};
 use libc;
-use std::collections::HashMap;
-use std::path::Path;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rand::Rng as _;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::os::unix::process::ExitStatusExt as _;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWriteExt};
 use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// What to do with a command that has outlived its timeout: send it `signal`, give it
+/// `grace` to exit, and SIGKILL it if it is still around after that.
+#[derive(Debug, Clone)]
+pub struct KillPolicy {
+    pub signal: i32,
+    pub grace: Duration,
+}
+
+impl Default for KillPolicy {
+    fn default() -> Self {
+        Self {
+            signal: libc::SIGTERM,
+            grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A failure we think is worth retrying.
+#[derive(Debug, Clone)]
+pub enum RetryOn {
+    /// The command exited with one of these codes.
+    ExitCodes(Vec<i32>),
+    /// The command's stderr matches this regex.
+    StderrMatches(Regex),
+}
+
+/// How to retry a flaky command. Delays start at `initial_delay`, are multiplied by
+/// `multiplier` after every attempt up to `max_delay`, and are then randomly moved by up
+/// to `jitter` (a fraction) either way. A failure is retried if it matches any of
+/// `retry_on`; if `retry_on` is empty, every failure is retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_on: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn on_exit_codes(mut self, codes: &[i32]) -> Self {
+        self.retry_on.push(RetryOn::ExitCodes(codes.to_vec()));
+        self
+    }
+
+    pub fn on_stderr(mut self, pattern: &str) -> Result<Self> {
+        self.retry_on
+            .push(RetryOn::StderrMatches(Regex::new(pattern)?));
+        Ok(self)
+    }
+
+    pub fn should_retry(&self, result: &CommandOutput) -> bool {
+        if result.success {
+            return false;
+        }
+        if self.retry_on.is_empty() {
+            return true;
+        }
+        self.retry_on.iter().any(|x| match x {
+            RetryOn::ExitCodes(codes) => codes.contains(&result.status_code),
+            RetryOn::StderrMatches(re) => {
+                re.is_match(&utils::string_or_empty_from_u8(&result.stderr))
+            }
+        })
+    }
+
+    /// How long to wait after attempt number `attempt` (starting at 1) has failed.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let factor = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range((1.0 - self.jitter)..(1.0 + self.jitter))
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64((base * factor).max(0.0))
+    }
+
+    fn needs_stderr(&self) -> bool {
+        self.retry_on
+            .iter()
+            .any(|x| matches!(x, RetryOn::StderrMatches(_)))
+    }
+}
+
+/// How much of our environment a command inherits, before its overrides are applied.
+/// This is the environment of the process we start - so, for a remote target, of ssh
+/// rather than the remote command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EnvMode {
+    #[default]
+    Inherit,
+    /// Nothing but the overrides.
+    Clear,
+    /// Only these variables (if we have them), and the overrides.
+    Allow(Vec<String>),
+}
+
+pub(crate) type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where a command's stdin comes from.
+#[derive(Clone)]
+pub enum Input {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+    /// Taken the first time the command is run.
+    Reader(Arc<Mutex<Option<BoxedReader>>>),
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Bytes(val) => write!(f, "Bytes({0} bytes)", val.len()),
+            Input::File(path) => write!(f, "File({path:?})"),
+            Input::Reader(_) => write!(f, "Reader"),
+        }
+    }
+}
+
+/// Point `cmd`'s stdin at `input`, returning anything we will need to copy to it once
+/// it has started.
+pub(crate) fn prepare_input(
+    cmd: &mut Command,
+    input: Option<&Input>,
+) -> Result<Option<BoxedReader>> {
+    match input {
+        None => {
+            cmd.stdin(Stdio::null());
+            Ok(None)
+        }
+        Some(Input::Bytes(val)) => {
+            cmd.stdin(Stdio::piped());
+            Ok(Some(Box::new(std::io::Cursor::new(val.clone()))))
+        }
+        Some(Input::File(path)) => {
+            let file = std::fs::File::open(path)
+                .map_err(|e| anyhow!("Cannot open input {0} - {e}", path.display()))?;
+            cmd.stdin(file);
+            Ok(None)
+        }
+        Some(Input::Reader(reader)) => {
+            let reader = reader
+                .lock()
+                .map_err(|_| anyhow!("Input reader lock is poisoned"))?
+                .take()
+                .ok_or(anyhow!("Input reader has already been used"))?;
+            cmd.stdin(Stdio::piped());
+            Ok(Some(reader))
+        }
+    }
+}
+
+/// A task echoing a stream, which returns whatever it captured.
+type CaptureTask = JoinHandle<Captured>;
 
 /// Wrapper in case we later want to add stuff to it.
 pub struct ChildProcess {
     pub child: Child,
+    /// Did we start this process in a new session? If so, signals go to the whole group.
+    pub session_leader: bool,
+    /// Echo tasks for stdout and stderr, if we are teeing their output.
+    output_tasks: Option<(CaptureTask, CaptureTask)>,
+    /// Audit log entry to complete when we know how the process ended.
+    audit: Option<PendingAudit>,
+    /// The task feeding stdin, if there is one.
+    input_task: Option<JoinHandle<std::io::Result<()>>>,
+    /// Lines of output, if we are reading them.
+    pub(crate) lines: Option<Arc<LineHub>>,
+    started: Instant,
+    exit_status: Option<ExitStatus>,
+    usage: Option<ResourceUsage>,
+    /// Tells our output readers to give up.
+    stop_reading: watch::Sender<bool>,
+}
+
+/// How long we keep reading output after the child has gone. Its descendants may hold
+/// the pipes open indefinitely.
+pub(crate) const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+impl ChildProcess {
+    /// Send a signal to the child, or to its process group if it leads a session.
+    pub fn signal(&self, sig: i32) -> Result<()> {
+        if self.exit_status.is_some() {
+            return Err(anyhow!("Child process has already exited"));
+        }
+        let id = self
+            .child
+            .id()
+            .ok_or(anyhow!("Child process has already exited"))?;
+        let pid = i32::try_from(id)?;
+        let target = if self.session_leader { -pid } else { pid };
+        if process::kill(target, sig) < 0 {
+            return Err(anyhow!(
+                "Cannot send signal {sig} to {target} - {0}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Wait until the child prints a line matching `pattern` - or has already printed one
+    /// recently - and return that line. Only works for processes whose output we read,
+    /// such as those from spawn_logged().
+    pub async fn wait_for_line(&self, pattern: &str, timeout: Duration) -> Result<String> {
+        let re = Regex::new(pattern)?;
+        self.lines
+            .as_ref()
+            .ok_or(anyhow!("We are not reading this process's output"))?
+            .wait_for_line(&re, timeout)
+            .await
+    }
+
+    /// Start copying `reader` to the child's stdin.
+    pub(crate) fn start_input(&mut self, reader: Option<BoxedReader>) -> Result<()> {
+        if let Some(mut reader) = reader {
+            let mut stdin = self
+                .child
+                .stdin
+                .take()
+                .ok_or(anyhow!("Cannot get process input"))?;
+            self.input_task = Some(tokio::spawn(async move {
+                tokio::io::copy(&mut reader, &mut stdin).await?;
+                stdin.shutdown().await
+            }));
+        }
+        Ok(())
+    }
+
+    /// Wait for stdin to have been written. A child which exits without reading all of
+    /// its input is not our problem; any other failure to write it is.
+    pub(crate) async fn finish_input(&mut self) -> Result<()> {
+        if let Some(mut task) = self.input_task.take() {
+            // Nor is a descendant which holds stdin open and doesn't read it.
+            let Ok(result) = time::timeout(DRAIN_GRACE, &mut task).await else {
+                task.abort();
+                return Ok(());
+            };
+            match result? {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                    return Err(anyhow!("Cannot write process input - {e}"));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the completion of this process to the audit log, if there is one.
+    pub(crate) fn record_completion(&mut self, output: &CommandOutput) {
+        if let Some(audit) = self.audit.take() {
+            audit.complete(output);
+        }
+    }
+
+    /// Wait for teed output to drain and return it as (stdout, stderr). Empty if we were
+    /// not teeing.
+    pub async fn captured_output(&mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (stdout, stderr) = self.captured().await?;
+        Ok((stdout.data, stderr.data))
+    }
+
+    pub(crate) async fn captured(&mut self) -> Result<(Captured, Captured)> {
+        match self.output_tasks.take() {
+            None => Ok(Default::default()),
+            Some((out_task, err_task)) => {
+                Ok((self.drain(out_task).await?, self.drain(err_task).await?))
+            }
+        }
+    }
+
+    /// A receiver for echo_lines(), which tells it when drain() has given up.
+    pub(crate) fn stop_signal(&self) -> watch::Receiver<bool> {
+        self.stop_reading.subscribe()
+    }
+
+    /// What an output task read, once the child has exited. If the output is still open
+    /// after DRAIN_GRACE, we stop reading and keep what we have.
+    pub(crate) async fn drain(&self, mut task: CaptureTask) -> Result<Captured> {
+        if let Ok(captured) = time::timeout(DRAIN_GRACE, &mut task).await {
+            return Ok(captured?);
+        }
+        println!("⏰ Output still open {DRAIN_GRACE:?} after the command exited - not waiting for the rest");
+        let _ = self.stop_reading.send(true);
+        Ok(task.await?)
+    }
+
+    /// Interleaved output, if we recorded it. Complete once the output has been read.
+    pub(crate) fn take_transcript(&self) -> Option<Transcript> {
+        self.lines.as_ref().and_then(|x| x.take_transcript())
+    }
+
+    /// Wait for the child to exit, and remember how it did so and what it cost. We look at
+    /// its usage before tokio reaps it, so `self.child` stays in step with the process.
+    pub(crate) async fn wait_exit(&mut self) -> Result<ExitStatus> {
+        if let Some(status) = self.exit_status {
+            return Ok(status);
+        }
+        let pid = match self.child.id() {
+            Some(id) => i32::try_from(id)?,
+            None => return Ok(self.child.wait().await?),
+        };
+        let usage = limits::wait_for_exit(pid, self.started).await?;
+        let status = self.child.wait().await?;
+        registry::untrack(u32::try_from(pid)?);
+        self.exit_status = Some(status);
+        self.usage = Some(usage);
+        Ok(status)
+    }
+
+    /// Wait for the child to exit. Use this rather than waiting for `child`, so that we
+    /// remember how it went and stop tracking it.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        self.wait_exit().await
+    }
+
+    /// Wait for the child to exit. If `timeout` elapses first, apply `policy` to get rid
+    /// of it. Returns the exit status and whether we timed out.
+    pub async fn wait_with_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+        policy: &KillPolicy,
+    ) -> Result<(ExitStatus, bool)> {
+        let limit = match timeout {
+            None => return Ok((self.wait_exit().await?, false)),
+            Some(limit) => limit,
+        };
+        if let Ok(status) = time::timeout(limit, self.wait_exit()).await {
+            return Ok((status?, false));
+        }
+        println!(
+            "⏰ Timed out after {limit:?} - sending signal {0}",
+            policy.signal
+        );
+        Ok((self.terminate(policy).await?, true))
+    }
+
+    /// Send the child the signal in `policy`; if it hasn't exited after the grace period,
+    /// SIGKILL it.
+    pub async fn terminate(&mut self, policy: &KillPolicy) -> Result<ExitStatus> {
+        // The child may have exited in the meantime, so failure here is not interesting.
+        let _ = self.signal(policy.signal);
+        if let Ok(status) = time::timeout(policy.grace, self.wait_exit()).await {
+            return status;
+        }
+        println!("⏰ Still running after {0:?} - killing", policy.grace);
+        let _ = self.signal(libc::SIGKILL);
+        self.wait_exit().await
+    }
+
+    /// What the child cost, once it has been waited for.
+    pub fn usage(&self) -> Option<&ResourceUsage> {
+        self.usage.as_ref()
+    }
 }
 
 // Reap on termination and return the id.
@@ -19,15 +416,35 @@ pub fn reap_on_termination(child: ChildProcess) -> Result<u32> {
         .child
         .id()
         .ok_or(anyhow!("Could not get child process id"))?;
-    tokio::spawn(async move { child.child.wait_with_output().await });
+    tokio::spawn(async move {
+        let result = child.child.wait_with_output().await;
+        registry::untrack(id);
+        result
+    });
     Ok(id)
 }
 
+#[derive(Debug, Clone)]
 pub struct CommandOutput {
     pub success: bool,
     pub status_code: i32,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    /// Did we give up waiting and kill the command?
+    pub timed_out: bool,
+    /// The signal that terminated the command, if any.
+    pub signal: Option<i32>,
+    /// How many times we ran the command.
+    pub attempts: u32,
+    /// What the (last attempt at the) command cost, if we know.
+    pub usage: Option<ResourceUsage>,
+    /// Did we drop some of the output to stay within our capture limit?
+    pub truncated: bool,
+    /// Where all of stdout went, if there was too much of it to keep.
+    pub stdout_spill: Option<PathBuf>,
+    pub stderr_spill: Option<PathBuf>,
+    /// Both streams, interleaved, if we were asked to record them.
+    pub transcript: Option<Transcript>,
 }
 
 impl CommandOutput {
@@ -37,6 +454,32 @@ impl CommandOutput {
             success: ok,
             stdout: Vec::new(),
             stderr: Vec::new(),
+            timed_out: false,
+            signal: None,
+            attempts: 1,
+            usage: None,
+            truncated: false,
+            stdout_spill: None,
+            stderr_spill: None,
+            transcript: None,
+        }
+    }
+
+    /// Delete the files we spilled output to, if any.
+    pub(crate) fn remove_spills(&self) {
+        for path in [&self.stdout_spill, &self.stderr_spill]
+            .into_iter()
+            .flatten()
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// As fake(), but with this on stdout.
+    pub fn fake_with_stdout(ok: bool, stdout: &str) -> Self {
+        Self {
+            stdout: stdout.as_bytes().to_vec(),
+            ..Self::fake(ok)
         }
     }
 
@@ -44,14 +487,18 @@ impl CommandOutput {
         if self.success {
             Ok(self)
         } else {
-            // TODO - make this part of the error!
-            self.print();
-            Err(anyhow!("{0}", err))
+            let error = redact::redact(&utils::string_or_empty_from_u8(&self.stderr));
+            let error = error.trim();
+            if error.is_empty() {
+                Err(anyhow!("{0}", err))
+            } else {
+                Err(anyhow!("{err}\n{error}"))
+            }
         }
     }
     pub fn print(&self) {
-        let output = &utils::string_or_empty_from_u8(&self.stdout);
-        let error = &utils::string_or_empty_from_u8(&self.stderr);
+        let output = &redact::redact(&utils::string_or_empty_from_u8(&self.stdout));
+        let error = &redact::redact(&utils::string_or_empty_from_u8(&self.stderr));
         println!("---------\n{output}\n{error}\n---------\n");
     }
     pub fn sanitise_stdout(&self) -> Result<String> {
@@ -82,6 +529,48 @@ pub struct CommandBuilder {
     /// Should we log the output, or return it?
     logged: bool,
     color: Option<Color>,
+    /// How long to wait before giving up on the command.
+    timeout: Option<Duration>,
+    kill_policy: KillPolicy,
+    /// When logging, should we capture the output as well?
+    tee: bool,
+    retry: Option<RetryPolicy>,
+    /// Who actually runs the command; the system if None.
+    runner: Option<Arc<dyn CommandRunner>>,
+    /// Where to record what we ran; the global audit log, if any, if None.
+    audit_log: Option<Arc<AuditLog>>,
+    /// Indices of arguments not to show anyone.
+    secret_args: HashSet<usize>,
+    /// Names of environment variables whose values are not to be shown.
+    secret_env: HashSet<String>,
+    /// Is whatever we write to stdin secret?
+    secret_input: bool,
+    /// What to feed the command on stdin; nothing if None.
+    input: Option<Input>,
+    /// Called with every line of output.
+    watchers: LineWatchers,
+    limits: ResourceLimits,
+    /// Run on a pseudo-terminal rather than pipes?
+    pty: bool,
+    /// Put in front of everything we print about this command.
+    label: Option<String>,
+    /// Where the command runs.
+    target: ExecutionTarget,
+    /// Values to mask wherever they appear in our arguments or environment.
+    masked_values: Vec<String>,
+    env_mode: EnvMode,
+    /// Variables not to inherit.
+    env_removed: BTreeSet<String>,
+    /// How much output to keep in memory.
+    capture_limit: Option<CaptureLimit>,
+    /// Record stdout and stderr together, in order?
+    transcript: bool,
+    /// Leave the child running if we are interrupted?
+    detached: bool,
+    /// Overrides the current execution mode.
+    mode: Option<ExecutionMode>,
+    /// Does the command change nothing, so that it is safe to run in a dry run?
+    read_only: bool,
 }
 
 impl Default for CommandBuilder {
@@ -104,6 +593,29 @@ impl CommandBuilder {
             create_new_session: false,
             logged: false,
             color: None,
+            timeout: None,
+            kill_policy: KillPolicy::default(),
+            tee: false,
+            retry: None,
+            runner: None,
+            audit_log: None,
+            secret_args: HashSet::new(),
+            secret_env: HashSet::new(),
+            secret_input: false,
+            input: None,
+            watchers: LineWatchers::default(),
+            limits: ResourceLimits::default(),
+            pty: false,
+            label: None,
+            target: ExecutionTarget::Local,
+            masked_values: Vec::new(),
+            env_mode: EnvMode::Inherit,
+            env_removed: BTreeSet::new(),
+            capture_limit: None,
+            transcript: false,
+            detached: false,
+            mode: None,
+            read_only: false,
         }
     }
 
@@ -112,6 +624,13 @@ impl CommandBuilder {
         self
     }
 
+    /// Log the output as it arrives, and also return it in the CommandOutput.
+    pub fn tee_output(&mut self) -> &mut Self {
+        self.logged = true;
+        self.tee = true;
+        self
+    }
+
     pub fn display(&mut self, what: &str) -> &mut Self {
         self.display_str = Some(what.to_string());
         self
@@ -122,6 +641,36 @@ impl CommandBuilder {
         self
     }
 
+    /// Put `label` in front of the command's output and description, docker-compose
+    /// style, so that you can tell it apart from other commands running at the same time.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Keep only so much of each output stream in memory; see CaptureLimit.
+    pub fn capture_limit(&mut self, limit: CaptureLimit) -> &mut Self {
+        self.capture_limit = Some(limit);
+        self
+    }
+
+    /// Record every line of output, with when it arrived and on which stream, in the
+    /// output's transcript. When logging, this captures the output as well.
+    pub fn record_transcript(&mut self) -> &mut Self {
+        self.transcript = true;
+        self
+    }
+
+    pub(crate) fn records_transcript(&self) -> bool {
+        self.transcript
+    }
+
+    /// Run the command on `target` - through sudo, in a container or on another host.
+    pub fn target(&mut self, target: &ExecutionTarget) -> &mut Self {
+        self.target = target.clone();
+        self
+    }
+
     pub fn ignore_failures(&mut self) -> &mut Self {
         self.throw_on_failure = false;
         self
@@ -147,7 +696,155 @@ impl CommandBuilder {
         self
     }
 
+    /// Don't track the child in the process registry, so that it is left running when
+    /// we are interrupted - for daemons and the like that should outlive us.
+    pub fn detached(&mut self) -> &mut Self {
+        self.detached = true;
+        self
+    }
+
+    /// Run the command on a pseudo-terminal, for programs that behave differently (or
+    /// won't run at all) without one. Everything the command prints - and the terminal's
+    /// echo of any input - ends up on stdout.
+    pub fn pty(&mut self) -> &mut Self {
+        self.pty = true;
+        self
+    }
+
+    /// Kill the command (according to the kill policy) if it runs for longer than this.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn kill_policy(&mut self, policy: KillPolicy) -> &mut Self {
+        self.kill_policy = policy;
+        self
+    }
+
+    /// How long to wait between the polite signal and SIGKILL.
+    pub fn grace_period(&mut self, grace: Duration) -> &mut Self {
+        self.kill_policy.grace = grace;
+        self
+    }
+
+    /// Retry failures of this command according to `policy`.
+    pub fn retry(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Run this command in `mode`, whatever the current mode is.
+    pub fn execution_mode(&mut self, mode: ExecutionMode) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Fix our mode as the current one, unless we have our own. For commands we hand to
+    /// tasks we spawn, which don't see the current mode.
+    pub(crate) fn inherit_execution_mode(&mut self) -> &mut Self {
+        if self.mode.is_none() {
+            self.mode = Some(ExecutionMode::current());
+        }
+        self
+    }
+
+    /// Print the command rather than running it.
+    pub fn dry_run(&mut self) -> &mut Self {
+        self.execution_mode(ExecutionMode::DryRun)
+    }
+
+    /// The command only looks at things, so run it even in a dry run.
+    pub fn read_only(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
+    }
+
+    /// The mode this command will run in.
+    pub fn get_execution_mode(&self) -> ExecutionMode {
+        match self.mode.clone().unwrap_or_else(ExecutionMode::current) {
+            ExecutionMode::DryRun if self.read_only => ExecutionMode::Execute,
+            mode => mode,
+        }
+    }
+
+    /// Have `runner` run this command rather than the system.
+    pub fn runner(&mut self, runner: Arc<dyn CommandRunner>) -> &mut Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Record this command in `log` rather than the global audit log.
+    pub fn audit_log(&mut self, log: Arc<AuditLog>) -> &mut Self {
+        self.audit_log = Some(log);
+        self
+    }
+
+    pub fn get_cmd(&self) -> Option<&str> {
+        self.cmd.as_deref()
+    }
+
+    pub fn get_args(&self) -> &[String] {
+        self.args.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_env(&self) -> Option<&HashMap<String, String>> {
+        self.env.as_ref()
+    }
+
+    pub fn get_cwd(&self) -> Option<&str> {
+        self.cwd.as_deref()
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn get_input(&self) -> Option<&Input> {
+        self.input.as_ref()
+    }
+
+    pub fn get_watchers(&self) -> &LineWatchers {
+        &self.watchers
+    }
+
+    pub fn get_target(&self) -> &ExecutionTarget {
+        &self.target
+    }
+
+    pub(crate) fn is_pty(&self) -> bool {
+        self.pty
+    }
+
+    /// This command as it will actually be run locally: wrapped to run on its target.
+    pub fn on_target(&self) -> Result<CommandBuilder> {
+        self.target.wrap(self)
+    }
+
+    /// Forget everything we were told about the environment - for when it has been
+    /// moved into the command line.
+    pub(crate) fn reset_env(&mut self) -> &mut Self {
+        self.env = None;
+        self.secret_env.clear();
+        self.env_mode = EnvMode::Inherit;
+        self.env_removed.clear();
+        self
+    }
+
+    pub(crate) fn clear_cwd(&mut self) -> &mut Self {
+        self.cwd = None;
+        self
+    }
+
+    /// Mask `values` wherever they appear in our arguments or environment.
+    pub(crate) fn mask_values(&mut self, values: &[String]) -> &mut Self {
+        self.masked_values
+            .extend(values.iter().filter(|x| !x.is_empty()).cloned());
+        self
+    }
+
     pub fn cmd(&mut self, cmd: &str, args: &[&str]) -> &mut Self {
+        self.secret_args.clear();
         self.cmd = Some(cmd.to_string());
         self.args = Some(args.iter().map(|x| x.to_string()).collect());
         self
@@ -162,6 +859,136 @@ impl CommandBuilder {
         self
     }
 
+    /// Add an argument which is shown as *** in descriptions, logs and errors.
+    pub fn secret_arg(&mut self, arg: &str) -> &mut Self {
+        self.more_args(&[arg]);
+        self.secret_args.insert(self.get_args().len() - 1);
+        self
+    }
+
+    /// Set an environment variable whose value is shown as *** in logs and errors.
+    pub fn secret_env_var(&mut self, name: &str, value: &str) -> &mut Self {
+        self.secret_env.insert(name.to_string());
+        self.env_var(name, value)
+    }
+
+    /// Apply `limits` to the command when it starts.
+    pub fn limits(&mut self, limits: ResourceLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Call `callback` with every line the command prints, on either stream.
+    pub fn on_line<F: Fn(OutputStream, &str) + Send + Sync + 'static>(
+        &mut self,
+        callback: F,
+    ) -> &mut Self {
+        self.watchers.add(Arc::new(callback));
+        self
+    }
+
+    /// Call `callback` with every line the command prints which matches `pattern`.
+    pub fn on_match<F: Fn(OutputStream, &str) + Send + Sync + 'static>(
+        &mut self,
+        pattern: &str,
+        callback: F,
+    ) -> Result<&mut Self> {
+        self.watchers.add_match(pattern, Arc::new(callback))?;
+        Ok(self)
+    }
+
+    /// Feed `input` to the command's stdin, whichever way it is run.
+    pub fn input(&mut self, input: Input) -> &mut Self {
+        self.input = Some(input);
+        self
+    }
+
+    pub fn input_bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.input(Input::Bytes(data.to_vec()))
+    }
+
+    pub fn input_file(&mut self, path: &Path) -> &mut Self {
+        self.input(Input::File(path.to_path_buf()))
+    }
+
+    /// Feed the command from `reader`. A reader can only be read once, so a command with
+    /// one can only be run once (and so cannot usefully be retried).
+    pub fn input_reader<R: AsyncRead + Send + Unpin + 'static>(&mut self, reader: R) -> &mut Self {
+        self.input(Input::Reader(Arc::new(Mutex::new(Some(Box::new(reader))))))
+    }
+
+    /// Mask whatever we write to stdin if it appears in output or errors.
+    pub fn secret_input(&mut self) -> &mut Self {
+        self.secret_input = true;
+        self
+    }
+
+    /// The values of our secret arguments and environment variables, and our input if it
+    /// is secret.
+    pub fn secrets(&self) -> Vec<String> {
+        let mut result: Vec<String> = self
+            .get_args()
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| self.secret_args.contains(idx))
+            .map(|(_, x)| x.clone())
+            .collect();
+        if let Some(env) = &self.env {
+            result.extend(
+                env.iter()
+                    .filter(|(k, _)| self.secret_env.contains(*k))
+                    .map(|(_, v)| v.clone()),
+            );
+        }
+        result.extend(self.masked_values.iter().cloned());
+        result.extend(self.input_secrets(self.input.as_ref()));
+        result
+    }
+
+    /// The lines of `input`, if our input is secret and we know what it is.
+    fn input_secrets(&self, input: Option<&Input>) -> Vec<String> {
+        match (self.secret_input, input) {
+            (true, Some(Input::Bytes(val))) => utils::string_or_empty_from_u8(val)
+                .lines()
+                .map(|x| x.trim_end_matches('\r'))
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Our arguments, with secret ones masked.
+    pub fn redacted_args(&self) -> Vec<String> {
+        self.get_args()
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| {
+                if self.secret_args.contains(&idx) {
+                    redact::MASK.to_string()
+                } else {
+                    redact::redact_with(x, &self.masked_values)
+                }
+            })
+            .collect()
+    }
+
+    /// Our environment overrides, with secret values masked.
+    pub fn redacted_env(&self) -> HashMap<String, String> {
+        self.env
+            .iter()
+            .flatten()
+            .map(|(k, v)| {
+                let val = if self.secret_env.contains(k) {
+                    redact::MASK.to_string()
+                } else {
+                    redact::redact_with(v, &self.masked_values)
+                };
+                (k.clone(), val)
+            })
+            .collect()
+    }
+
     pub fn env_var(&mut self, name: &str, value: &str) -> &mut Self {
         if let Some(e) = &mut self.env {
             e.insert(name.to_string(), value.to_string());
@@ -178,6 +1005,139 @@ impl CommandBuilder {
         self
     }
 
+    pub fn env_mode(&mut self, mode: EnvMode) -> &mut Self {
+        self.env_mode = mode;
+        self
+    }
+
+    /// Inherit none of our environment.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_mode(EnvMode::Clear)
+    }
+
+    /// Inherit only these variables from our environment.
+    pub fn env_allow(&mut self, names: &[&str]) -> &mut Self {
+        self.env_mode(EnvMode::Allow(
+            names.iter().map(|x| x.to_string()).collect(),
+        ))
+    }
+
+    /// Don't pass `name` on, whether we inherit it or it was set with env_var().
+    pub fn env_remove(&mut self, name: &str) -> &mut Self {
+        if let Some(env) = &mut self.env {
+            env.remove(name);
+        }
+        self.env_removed.insert(name.to_string());
+        self
+    }
+
+    pub fn get_env_mode(&self) -> &EnvMode {
+        &self.env_mode
+    }
+
+    /// Put `dir` at the start of the command's PATH.
+    pub fn path_prepend(&mut self, dir: &str) -> &mut Self {
+        let path = match self.current_path() {
+            Some(path) if !path.is_empty() => format!("{dir}:{path}"),
+            _ => dir.to_string(),
+        };
+        self.env_var("PATH", &path)
+    }
+
+    /// Put `dir` at the end of the command's PATH.
+    pub fn path_append(&mut self, dir: &str) -> &mut Self {
+        let path = match self.current_path() {
+            Some(path) if !path.is_empty() => format!("{path}:{dir}"),
+            _ => dir.to_string(),
+        };
+        self.env_var("PATH", &path)
+    }
+
+    /// The PATH the command would get as things stand, if any.
+    fn current_path(&self) -> Option<String> {
+        if let Some(path) = self.env.as_ref().and_then(|x| x.get("PATH")) {
+            return Some(path.clone());
+        }
+        if !self.inherits("PATH") {
+            return None;
+        }
+        env::var("PATH").ok()
+    }
+
+    /// Does the command inherit all of our environment?
+    pub(crate) fn inherits_all(&self) -> bool {
+        self.env_mode == EnvMode::Inherit && self.env_removed.is_empty()
+    }
+
+    /// Does the command inherit `name` from our environment?
+    fn inherits(&self, name: &str) -> bool {
+        if self.env_removed.contains(name) {
+            return false;
+        }
+        match &self.env_mode {
+            EnvMode::Inherit => true,
+            EnvMode::Clear => false,
+            EnvMode::Allow(names) => names.iter().any(|x| x == name),
+        }
+    }
+
+    /// Arguments for env(1) to give a command the environment this one would get, for
+    /// when we can't set it directly. Empty if there is nothing to do.
+    pub(crate) fn env_words(&self, redacted: bool) -> Vec<String> {
+        self.env_words_for(redacted, false, true)
+    }
+
+    /// As env_words(), but for running the command on another machine or in a container:
+    /// not PATH, whose value is ours and means nothing there.
+    pub(crate) fn remote_env_words(&self) -> Vec<String> {
+        self.env_words_for(false, false, false)
+    }
+
+    /// As env_words(), but for running the command as another user: only our explicit
+    /// overrides, and not PATH - a PATH with directories the caller can write to would
+    /// let them choose what root runs.
+    pub(crate) fn privileged_env_words(&self) -> Vec<String> {
+        self.env_words_for(false, true, false)
+    }
+
+    fn env_words_for(&self, redacted: bool, privileged: bool, with_path: bool) -> Vec<String> {
+        let mut words = Vec::new();
+        match &self.env_mode {
+            EnvMode::Inherit => {
+                for name in &self.env_removed {
+                    words.extend(["-u".to_string(), name.clone()]);
+                }
+            }
+            EnvMode::Clear => words.push("-i".to_string()),
+            EnvMode::Allow(names) => {
+                words.push("-i".to_string());
+                for name in names {
+                    let overridden = self.env.as_ref().is_some_and(|x| x.contains_key(name));
+                    if privileged || overridden || !self.inherits(name) {
+                        continue;
+                    }
+                    if let Ok(val) = env::var(name) {
+                        let val = if redacted { redact::redact(&val) } else { val };
+                        words.push(format!("{name}={val}"));
+                    }
+                }
+            }
+        }
+        let mut overrides: Vec<(String, String)> = if redacted {
+            self.redacted_env().into_iter().collect()
+        } else {
+            self.get_env()
+                .iter()
+                .flat_map(|x| x.iter())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        overrides.retain(|(k, _)| with_path || k != "PATH");
+        overrides.sort();
+        words.extend(overrides.into_iter().map(|(k, v)| format!("{k}={v}")));
+        words
+    }
+
     pub fn current_dir(&mut self, dir: &Path) -> Result<&mut Self> {
         Ok(self.cwd(&utils::string_from_path(dir)?))
     }
@@ -188,39 +1148,117 @@ impl CommandBuilder {
     }
 
     pub fn describe_command(&self) -> Result<String> {
+        if !self.target.is_local() {
+            return self.on_target()?.describe_command();
+        }
+        let cwd_str = self.cwd.as_ref().map_or("", |x| x.as_str());
+        Ok(format!("[{cwd_str}]$ {0}", self.describe_command_line()?))
+    }
+
+    /// The command as a line you can paste into a POSIX shell, with any working directory
+    /// and environment overrides. Secrets are still masked.
+    pub fn to_shell_command(&self) -> Result<String> {
+        if !self.target.is_local() {
+            return self.on_target()?.to_shell_command();
+        }
+        self.render_shell(true, false)
+    }
+
+    /// The command as a line for a remote shell, ignoring our target, with secrets in
+    /// the clear.
+    pub(crate) fn shell_command_line(&self) -> Result<String> {
+        self.render_shell(false, true)
+    }
+
+    fn render_shell(&self, redacted: bool, remote: bool) -> Result<String> {
+        let cmd_name = self.cmd.as_ref().ok_or(anyhow!("No command specified"))?;
+        let mut words: Vec<String> = Vec::new();
+        let env = if remote {
+            self.remote_env_words()
+        } else {
+            self.env_words(redacted)
+        };
+        if !env.is_empty() {
+            words.push("env".to_string());
+            words.extend(env.iter().map(|x| utils::shell_quote(x)));
+        }
+        words.push(utils::shell_quote(cmd_name));
+        let args = if redacted {
+            self.redacted_args()
+        } else {
+            self.get_args().to_vec()
+        };
+        for arg in args {
+            words.push(utils::shell_quote(&arg));
+        }
+        let line = words.join(" ");
+        match &self.cwd {
+            // In a subshell, so that the cd doesn't affect anything after us.
+            Some(cwd) => Ok(format!("(cd {0} && {line})", utils::shell_quote(cwd))),
+            None => Ok(line),
+        }
+    }
+
+    /// The command and its arguments, as displayed, without the working directory.
+    pub(crate) fn describe_command_line(&self) -> Result<String> {
+        if !self.target.is_local() {
+            return self.on_target()?.describe_command_line();
+        }
         let cmd_name = self
             .cmd
             .as_ref()
             .ok_or(anyhow!("No command specified"))?
             .clone();
-        let cwd_str = self.cwd.as_ref().map_or("", |x| x.as_str());
-        let mut result = String::new();
         if let Some(val) = &self.display_str {
-            result.push_str(&format!("[{cwd_str}]$ {val}"));
+            Ok(redact::redact_with(val, &self.secrets()))
         } else {
-            let space_args = if let Some(args) = &self.args {
-                args.join(" ")
-            } else {
-                "".to_string()
-            };
-            result.push_str(&format!("[{cwd_str}]$ {0} {space_args}", &cmd_name));
+            let space_args = self.redacted_args().join(" ");
+            Ok(format!("{0} {space_args}", &cmd_name))
         }
-        Ok(result)
     }
+
     /// Common bits of starting a new process.
-    fn make_command(&self) -> Result<Command> {
+    pub(crate) fn make_command(&self) -> Result<Command> {
+        if self.display_command {
+            match &self.label {
+                Some(label) => println!("{label} | {0}", self.describe_command()?),
+                None => println!("{0}", self.describe_command()?),
+            }
+        }
+        self.build_command()
+    }
+
+    /// Build the process to run, without announcing it.
+    pub(crate) fn build_command(&self) -> Result<Command> {
+        if !self.target.is_local() {
+            return self.on_target()?.build_command();
+        }
         let cmd_name = self
             .cmd
             .as_ref()
             .ok_or(anyhow!("No command specified"))?
             .clone();
         let mut cmd = Command::new(cmd_name);
-        if self.display_command {
-            println!("{0}", self.describe_command()?)
-        }
         if let Some(args) = &self.args {
             cmd.args(args);
         }
+        match &self.env_mode {
+            EnvMode::Inherit => (),
+            EnvMode::Clear => {
+                cmd.env_clear();
+            }
+            EnvMode::Allow(names) => {
+                cmd.env_clear();
+                for name in names {
+                    if let Some(val) = env::var_os(name) {
+                        cmd.env(name, val);
+                    }
+                }
+            }
+        }
+        for name in &self.env_removed {
+            cmd.env_remove(name);
+        }
         if let Some(env) = &self.env {
             cmd.envs(env);
         }
@@ -235,13 +1273,63 @@ impl CommandBuilder {
                 });
             }
         }
+        if !self.limits.is_empty() {
+            let limits = self.limits.clone();
+            unsafe {
+                cmd.pre_exec(move || limits.apply());
+            }
+        }
         Ok(cmd)
     }
 
+    pub(crate) fn spawn_command(&self, cmd: &mut Command) -> Result<ChildProcess> {
+        if self.get_execution_mode().is_dry_run() {
+            return Err(anyhow!(
+                "Cannot start {0} in a dry run",
+                self.describe_command_line()?
+            ));
+        }
+        let child = cmd.spawn().map_err(|e| CommandError::spawn(self, e))?;
+        // Record what we actually ran: through sudo, in a container and so on.
+        let wrapped;
+        let audited = if self.target.is_local() {
+            self
+        } else {
+            wrapped = self.on_target()?;
+            &wrapped
+        };
+        let audit = self
+            .audit_log
+            .clone()
+            .or_else(AuditLog::global)
+            .map(|log| log.record_spawn(audited, child.id()));
+        let proc = ChildProcess {
+            child,
+            session_leader: self.create_new_session,
+            output_tasks: None,
+            audit,
+            input_task: None,
+            lines: None,
+            started: Instant::now(),
+            exit_status: None,
+            usage: None,
+            stop_reading: watch::channel(false).0,
+        };
+        self.track(&proc);
+        Ok(proc)
+    }
+
+    /// Add `proc` to the process registry, unless we are detached.
+    pub(crate) fn track(&self, proc: &ChildProcess) {
+        if let (false, Some(pid)) = (self.detached, proc.child.id()) {
+            let description = self.describe_command_line().unwrap_or_default();
+            registry::track(pid, proc.session_leader, &description);
+        }
+    }
+
     pub async fn spawn(&self) -> Result<ChildProcess> {
         let mut cmd = self.make_command()?;
-        let child = cmd.spawn()?;
-        Ok(ChildProcess { child })
+        self.spawn_command(&mut cmd)
     }
 
     pub async fn spawn_logged(&self) -> Result<ChildProcess> {
@@ -249,89 +1337,229 @@ impl CommandBuilder {
     }
 
     pub async fn spawn_logged_with_input(&self, input: Option<&str>) -> Result<ChildProcess> {
+        match input {
+            Some(val) => {
+                let input = Input::Bytes(val.as_bytes().to_vec());
+                self.spawn_reading(Some(&input), self.tee, true).await
+            }
+            None => {
+                self.spawn_reading(self.input.as_ref(), self.tee, true)
+                    .await
+            }
+        }
+    }
+
+    /// Spawn, reading the output - for line watchers and wait_for_line() - but not
+    /// printing it.
+    pub async fn spawn_quiet(&self) -> Result<ChildProcess> {
+        self.spawn_reading(self.input.as_ref(), false, false).await
+    }
+
+    /// Spawn the command on a pseudo-terminal and return a session to talk to it with.
+    pub async fn spawn_session(&self) -> Result<PtySession> {
+        PtySession::spawn(self).await
+    }
+
+    async fn spawn_reading(
+        &self,
+        input: Option<&Input>,
+        capture: bool,
+        echo: bool,
+    ) -> Result<ChildProcess> {
         let mut cmd = self.make_command()?;
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
-        if input.is_some() {
-            cmd.stdin(Stdio::piped());
-        } else {
-            cmd.stdin(Stdio::null());
+        let to_write = prepare_input(&mut cmd, input)?;
+        let mut proc = self.spawn_command(&mut cmd)?;
+        let output = proc
+            .child
+            .stdout
+            .take()
+            .ok_or(anyhow!("Cannot get process output"))?;
+        let err = proc
+            .child
+            .stderr
+            .take()
+            .ok_or(anyhow!("Cannot get process error"))?;
+        let mut secrets = self.secrets();
+        secrets.extend(self.input_secrets(input));
+        let hub = LineHub::new(&self.watchers, 2, self.transcript);
+        let mut out_options = self.echo_options(OutputStream::Stdout, capture, &secrets, &hub);
+        let mut err_options = self.echo_options(OutputStream::Stderr, capture, &secrets, &hub);
+        out_options.echo = echo;
+        err_options.echo = echo;
+        out_options.stop = Some(proc.stop_signal());
+        err_options.stop = Some(proc.stop_signal());
+        let out_task = tokio::spawn(echo_lines(output, out_options));
+        let err_task = tokio::spawn(echo_lines(err, err_options));
+        proc.lines = Some(hub);
+        if capture {
+            proc.output_tasks = Some((out_task, err_task));
+        }
+        proc.start_input(to_write)?;
+        Ok(proc)
+    }
+
+    pub(crate) fn echo_options(
+        &self,
+        stream: OutputStream,
+        capture: bool,
+        secrets: &[String],
+        hub: &Arc<LineHub>,
+    ) -> EchoOptions {
+        EchoOptions {
+            stream,
+            prefix: stream.prefix(),
+            color: self.color,
+            capture,
+            echo: true,
+            secrets: secrets.to_vec(),
+            hub: Some(hub.clone()),
+            label: self.label.clone(),
+            limit: self.capture_limit,
+            stop: None,
+        }
+    }
+
+    /// Turn an exit status into a CommandOutput.
+    pub(crate) fn make_output(
+        &self,
+        status: ExitStatus,
+        timed_out: bool,
+        stdout: Captured,
+        stderr: Captured,
+        usage: Option<ResourceUsage>,
+    ) -> CommandOutput {
+        CommandOutput {
+            success: status.success() && !timed_out,
+            status_code: status.code().unwrap_or(-1),
+            stdout: stdout.data,
+            stderr: stderr.data,
+            timed_out,
+            signal: status.signal(),
+            attempts: 1,
+            usage,
+            truncated: stdout.truncated || stderr.truncated,
+            stdout_spill: stdout.spill,
+            stderr_spill: stderr.spill,
+            transcript: None,
+        }
+    }
+
+    /// Fail if the command failed and we were asked to.
+    pub(crate) fn check(&self, result: CommandOutput) -> Result<CommandOutput> {
+        if self.throw_on_failure && !result.success {
+            return Err(CommandError::from_output(self, &result).into());
+        }
+        Ok(result)
+    }
+
+    /// Wait for a process we started, applying our timeout.
+    pub(crate) async fn wait_for(&self, proc: &mut ChildProcess) -> Result<(ExitStatus, bool)> {
+        proc.wait_with_timeout(self.timeout, &self.kill_policy)
+            .await
+    }
+
+    pub(crate) async fn attempt_logged(&self) -> Result<CommandOutput> {
+        if self.pty {
+            return pty::attempt(self, true).await;
+        }
+        // A recording is only as good as the output in it.
+        let capture = self.tee
+            || self.transcript
+            || self.retry.as_ref().is_some_and(|x| x.needs_stderr())
+            || matches!(self.get_execution_mode(), ExecutionMode::Record(_));
+        let mut child = self
+            .spawn_reading(self.input.as_ref(), capture, true)
+            .await?;
+        let (status, timed_out) = self.wait_for(&mut child).await?;
+        child.finish_input().await?;
+        let (stdout, stderr) = child.captured().await?;
+        let mut result =
+            self.make_output(status, timed_out, stdout, stderr, child.usage().cloned());
+        result.transcript = child.take_transcript();
+        child.record_completion(&result);
+        Ok(result)
+    }
+
+    pub(crate) async fn attempt_for_output(&self) -> Result<CommandOutput> {
+        if self.pty {
+            return pty::attempt(self, false).await;
         }
-        let mut child = cmd.spawn()?;
-        let output = child
+        let mut cmd = self.make_command()?;
+        let to_write = prepare_input(&mut cmd, self.input.as_ref())?;
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut proc = self.spawn_command(&mut cmd)?;
+        proc.start_input(to_write)?;
+        let output = proc
+            .child
             .stdout
             .take()
             .ok_or(anyhow!("Cannot get process output"))?;
-        let err = child
+        let err = proc
+            .child
             .stderr
             .take()
             .ok_or(anyhow!("Cannot get process error"))?;
-        let current_color = self.color;
-        tokio::spawn(async move {
-            let mut out_reader = BufReader::new(output).lines();
-            while let Some(line) = out_reader.next_line().await.unwrap_or(None) {
-                let trimmed = line.trim();
-                if !trimmed.is_empty() {
-                    let mut real_line = String::new();
-                    real_line.push('\r');
-                    real_line.push('\n');
-                    real_line.push('>');
-                    real_line.push_str(trimmed);
-                    if let Some(color) = current_color {
-                        print!("{}", real_line.color(color));
-                    } else {
-                        let _ = tokio::io::stdout().write_all(real_line.as_bytes()).await;
-                    }
+        let hub = LineHub::new(&self.watchers, 2, self.transcript);
+        let secrets = self.secrets();
+        let mut out_options = self.echo_options(OutputStream::Stdout, true, &secrets, &hub);
+        let mut err_options = self.echo_options(OutputStream::Stderr, true, &secrets, &hub);
+        out_options.echo = false;
+        err_options.echo = false;
+        out_options.stop = Some(proc.stop_signal());
+        err_options.stop = Some(proc.stop_signal());
+        let out_task = tokio::spawn(echo_lines(output, out_options));
+        let err_task = tokio::spawn(echo_lines(err, err_options));
+        proc.lines = Some(hub);
+        let (status, timed_out) = self.wait_for(&mut proc).await?;
+        proc.finish_input().await?;
+        let stdout = proc.drain(out_task).await?;
+        let stderr = proc.drain(err_task).await?;
+        let mut result = self.make_output(status, timed_out, stdout, stderr, proc.usage().cloned());
+        result.transcript = proc.take_transcript();
+        proc.record_completion(&result);
+        Ok(result)
+    }
+
+    /// Run the command, retrying according to the retry policy, if any.
+    async fn run_attempts(&self, logged: bool) -> Result<CommandOutput> {
+        let mode = self.get_execution_mode();
+        if mode.is_dry_run() {
+            println!("{0}  # 🔍 dry run", self.to_shell_command()?);
+            return Ok(CommandOutput::fake(true));
+        }
+        let mut attempt = 1;
+        loop {
+            let mut result = match &self.runner {
+                Some(runner) => runner.run_once(self, logged).await?,
+                None if logged => self.attempt_logged().await?,
+                None => self.attempt_for_output().await?,
+            };
+            result.attempts = attempt;
+            if let Some(policy) = &self.retry {
+                if attempt < policy.max_attempts && policy.should_retry(&result) {
+                    let delay = policy.delay(attempt);
+                    println!(
+                        "🔁 Attempt {attempt}/{0} failed - {1}; retrying in {delay:?}",
+                        policy.max_attempts, result.status_code
+                    );
+                    result.remove_spills();
+                    time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
                 }
             }
-        });
-        tokio::spawn(async move {
-            let mut err_reader = BufReader::new(err).lines();
-            while let Some(line) = err_reader.next_line().await.unwrap_or(None) {
-                let trimmed = line.trim();
-                if !trimmed.is_empty() {
-                    let mut real_line = String::new();
-                    real_line.push('\r');
-                    real_line.push('\n');
-                    real_line.push('!');
-                    real_line.push_str(trimmed);
-                    if let Some(color) = current_color {
-                        print!("{}", real_line.color(color));
-                    } else {
-                        let _ = tokio::io::stdout().write_all(real_line.as_bytes()).await;
-                    }
-                }
+            if let ExecutionMode::Record(recording) = &mode {
+                recording.record(self, &result);
             }
-        });
-        if let Some(val) = input {
-            let mut input = child
-                .stdin
-                .take()
-                .ok_or(anyhow!("Cannot get process input"))?;
-            let val_copy = val.to_string();
-            tokio::spawn(async move {
-                if let Err(errval) = input.write_all(val_copy.as_bytes()).await {
-                    println!("Couldn't write stdin - {0:?}", errval);
-                }
-            });
+            return self.check(result);
         }
-
-        Ok(ChildProcess { child })
     }
 
     pub async fn run_logged(&self) -> Result<CommandOutput> {
-        let mut child = self.spawn_logged().await?;
-        let result = child.child.wait().await?;
-        let code = result.code().unwrap_or(-1);
-        if self.throw_on_failure && !result.success() {
-            return Err(anyhow!("Command failed  - {0}", code));
-        }
-        Ok(CommandOutput {
-            success: result.success(),
-            status_code: code,
-            stdout: vec![],
-            stderr: vec![],
-        })
+        self.run_attempts(true).await
     }
 
     pub async fn run(&self) -> Result<CommandOutput> {
@@ -342,28 +1570,210 @@ impl CommandBuilder {
         }
     }
 
-    pub async fn run_logged_with_input(&self, indata: &str) -> Result<()> {
-        let mut proc = self.spawn_logged_with_input(Some(indata)).await?;
-        proc.child.wait().await?;
-        Ok(())
+    pub async fn run_logged_with_input(&self, indata: &str) -> Result<CommandOutput> {
+        self.clone()
+            .input(Input::Bytes(indata.as_bytes().to_vec()))
+            .run_logged()
+            .await
     }
 
     pub async fn run_for_output(&self) -> Result<CommandOutput> {
-        let mut cmd = self.make_command()?;
-        let out = cmd.output().await?;
-        let result_code = out.status.code().unwrap_or(-1);
-        if self.throw_on_failure && !out.status.success() {
-            let output = &utils::string_or_empty_from_u8(&out.stdout);
-            let error = &utils::string_or_empty_from_u8(&out.stderr);
-            return Err(anyhow!("Command failed - {result_code}\n{output}\n{error}"));
-        }
-        Ok(CommandOutput {
-            success: out.status.success(),
-            status_code: result_code,
-            stdout: out.stdout,
-            stderr: out.stderr,
+        self.run_attempts(false).await
+    }
+
+    /// Run the command for its stdout, which must be JSON, and parse it.
+    pub async fn run_json<T: DeserializeOwned>(&self) -> Result<T> {
+        let stdout = self.run_for_stdout().await?;
+        serde_json::from_str(&stdout)
+            .map_err(|e| self.parse_error("JSON", &stdout, Some(e.line()), &e))
+    }
+
+    /// Run the command for its stdout, which must be YAML, and parse it.
+    pub async fn run_yaml<T: DeserializeOwned>(&self) -> Result<T> {
+        let stdout = self.run_for_stdout().await?;
+        serde_yaml::from_str(&stdout).map_err(|e| {
+            let line = e.location().map(|x| x.line());
+            self.parse_error("YAML", &stdout, line, &e)
+        })
+    }
+
+    /// Run the command for the non-blank lines of its stdout, with trailing whitespace
+    /// removed.
+    pub async fn run_lines(&self) -> Result<Vec<String>> {
+        Ok(self
+            .run_for_stdout()
+            .await?
+            .lines()
+            .map(|x| x.trim_end().to_string())
+            .filter(|x| !x.is_empty())
+            .collect())
+    }
+
+    /// Run the command for its stdout, failing if it fails whether or not we throw on
+    /// failure.
+    async fn run_for_stdout(&self) -> Result<String> {
+        let output = self.run_for_output().await?;
+        if !output.success {
+            return Err(CommandError::from_output(self, &output).into());
+        }
+        String::from_utf8(output.stdout).map_err(|e| {
+            let text = String::from_utf8_lossy(e.as_bytes()).to_string();
+            self.parse_error("UTF-8", &text, None, &e)
         })
     }
+
+    /// Describe a failure to parse `output`, showing the lines leading up to `line`
+    /// (counting from 1) if we know it, or the start of the output if we don't.
+    fn parse_error(
+        &self,
+        what: &str,
+        output: &str,
+        line: Option<usize>,
+        err: &dyn fmt::Display,
+    ) -> anyhow::Error {
+        const CONTEXT_LINES: usize = 3;
+        const MAX_LINE: usize = 200;
+        let lines: Vec<&str> = output.lines().collect();
+        let end = line.unwrap_or(CONTEXT_LINES).clamp(1, lines.len().max(1));
+        let start = end.saturating_sub(CONTEXT_LINES);
+        let snippet: Vec<String> = lines
+            .iter()
+            .enumerate()
+            .take(end)
+            .skip(start)
+            .map(|(idx, text)| {
+                let text: String = text.chars().take(MAX_LINE).collect();
+                format!("{0:>4} | {text}", idx + 1)
+            })
+            .collect();
+        let description = self
+            .describe_command()
+            .unwrap_or_else(|_| "<no command>".to_string());
+        anyhow!(
+            "Cannot parse output of {description} as {what} - {err}\n{0}",
+            redact::redact_with(&snippet.join("\n"), &self.secrets())
+        )
+    }
+}
+
+/// How to treat one output stream of a process.
+#[derive(Clone)]
+pub(crate) struct EchoOptions {
+    pub stream: OutputStream,
+    pub prefix: char,
+    pub color: Option<Color>,
+    /// Return everything we read?
+    pub capture: bool,
+    /// Print lines as they arrive?
+    pub echo: bool,
+    /// Mask these, as well as any registered secrets, when printing.
+    pub secrets: Vec<String>,
+    /// Where to send lines for watchers.
+    pub hub: Option<Arc<LineHub>>,
+    pub label: Option<String>,
+    /// How much of what we capture to keep; everything if None.
+    pub limit: Option<CaptureLimit>,
+    /// Stop reading when this turns true.
+    pub stop: Option<watch::Receiver<bool>>,
+}
+
+/// Resolves once `stop` turns true; never, if there is nothing to turn it.
+async fn stopped(stop: &mut Option<watch::Receiver<bool>>) {
+    if let Some(stop) = stop {
+        if stop.wait_for(|x| *x).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// The longest line we echo or hand to watchers; we drop the rest of longer lines.
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Add `data` to `line`, up to MAX_LINE_BYTES. Returns whether we had to drop any.
+fn append_capped(line: &mut Vec<u8>, data: &[u8]) -> bool {
+    let room = MAX_LINE_BYTES.saturating_sub(line.len());
+    line.extend_from_slice(&data[..room.min(data.len())]);
+    data.len() > room
+}
+
+/// Echo a line, and pass it to watchers, as `options` say.
+async fn emit_line(line: &[u8], cut_short: bool, options: &EchoOptions) {
+    // Watchers and transcripts see what we print, not the secrets in it.
+    let text = redact::redact_with(&String::from_utf8_lossy(line), &options.secrets);
+    if let Some(hub) = &options.hub {
+        hub.publish(options.stream, text.trim_end_matches(['\r', '\n']));
+    }
+    let trimmed = text.trim();
+    if options.echo && !trimmed.is_empty() {
+        let label = options
+            .label
+            .as_ref()
+            .map(|x| format!("{x} | "))
+            .unwrap_or_default();
+        let marker = if cut_short {
+            " [... line truncated ...]"
+        } else {
+            ""
+        };
+        let real_line = format!("\r\n{label}{0}{trimmed}{marker}", options.prefix);
+        if let Some(color) = options.color {
+            print!("{}", real_line.color(color));
+        } else {
+            let _ = tokio::io::stdout().write_all(real_line.as_bytes()).await;
+        }
+    }
+}
+
+/// Echo lines from `reader` to stdout as `options` say. Returns what we read, within
+/// the capture limit, if we were asked to capture it. We read in chunks, so that
+/// neither the limit nor the longest line we echo is at the mercy of the command.
+pub(crate) async fn echo_lines<R: AsyncRead + Unpin>(
+    mut reader: R,
+    options: EchoOptions,
+) -> Captured {
+    let mut captured = Capture::new(options.limit, options.stream);
+    let wants_lines = options.echo || options.hub.is_some();
+    let mut chunk = vec![0u8; 8192];
+    let mut line = Vec::new();
+    let mut cut_short = false;
+    let mut stop = options.stop.clone();
+    loop {
+        let count = tokio::select! {
+            result = reader.read(&mut chunk) => match result {
+                Ok(0) | Err(_) => break,
+                Ok(count) => count,
+            },
+            _ = stopped(&mut stop) => break,
+        };
+        let data = &chunk[..count];
+        if options.capture {
+            captured.push(data).await;
+        }
+        if !wants_lines {
+            continue;
+        }
+        let mut rest = data;
+        while let Some(end) = rest.iter().position(|x| *x == b'\n') {
+            cut_short |= append_capped(&mut line, &rest[..=end]);
+            emit_line(&line, cut_short, &options).await;
+            line.clear();
+            cut_short = false;
+            rest = &rest[end + 1..];
+        }
+        cut_short |= append_capped(&mut line, rest);
+    }
+    if !line.is_empty() {
+        emit_line(&line, cut_short, &options).await;
+    }
+    if let Some(hub) = &options.hub {
+        hub.close_stream();
+    }
+    if options.capture {
+        captured.finish().await
+    } else {
+        Captured::default()
+    }
 }
 
 #[derive(Debug)]
@@ -379,6 +1789,19 @@ impl BackgroundCommand {
         } else {
             result = Command::new(cmd).args(args).spawn()?;
         }
+        if let Some(pid) = result.id() {
+            registry::track(pid, false, cmd);
+        }
         Ok(BackgroundCommand { running: result })
     }
+
+    /// Wait for the command to exit, and stop tracking it.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        let pid = self.running.id();
+        let status = self.running.wait().await?;
+        if let Some(pid) = pid {
+            registry::untrack(pid);
+        }
+        Ok(status)
+    }
 }
@@ -1,14 +1,25 @@
 use crate::commands::CommandBuilder;
+use crate::runner::{self, CommandRunner};
 use anyhow::{anyhow, Result};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time;
 
 pub async fn is_container_running(container_name: &str) -> Result<bool> {
+    is_container_running_with(&runner::system(), container_name).await
+}
+
+pub async fn is_container_running_with(
+    runner: &Arc<dyn CommandRunner>,
+    container_name: &str,
+) -> Result<bool> {
     let is_running = CommandBuilder::new()
+        .runner(runner.clone())
         .cmd(
             "docker",
             &["inspect", "-f", "{{.State.Running}}", container_name],
         )
+        .read_only()
         .run_logged()
         .await?;
     Ok(is_running.status_code == 0)
@@ -18,9 +29,19 @@ pub async fn wait_for_container_running(
     container_name: &str,
     wait_ms: u64,
     poll_interval_ms: u64,
+) -> Result<bool> {
+    wait_for_container_running_with(&runner::system(), container_name, wait_ms, poll_interval_ms)
+        .await
+}
+
+pub async fn wait_for_container_running_with(
+    runner: &Arc<dyn CommandRunner>,
+    container_name: &str,
+    wait_ms: u64,
+    poll_interval_ms: u64,
 ) -> Result<bool> {
     for _ in 0..(wait_ms / poll_interval_ms) {
-        if is_container_status_running(container_name).await? {
+        if is_container_status_running_with(runner, container_name).await? {
             return Ok(true);
         }
         time::sleep(Duration::from_millis(poll_interval_ms)).await;
@@ -33,9 +54,19 @@ pub async fn wait_for_container_stopped(
     container_name: &str,
     wait_ms: u64,
     poll_interval_ms: u64,
+) -> Result<bool> {
+    wait_for_container_stopped_with(&runner::system(), container_name, wait_ms, poll_interval_ms)
+        .await
+}
+
+pub async fn wait_for_container_stopped_with(
+    runner: &Arc<dyn CommandRunner>,
+    container_name: &str,
+    wait_ms: u64,
+    poll_interval_ms: u64,
 ) -> Result<bool> {
     for _ in 0..(wait_ms / poll_interval_ms) {
-        if !is_container_status_running(container_name).await? {
+        if !is_container_status_running_with(runner, container_name).await? {
             return Ok(true);
         }
         time::sleep(Duration::from_millis(poll_interval_ms)).await;
@@ -45,6 +76,13 @@ pub async fn wait_for_container_stopped(
 }
 
 pub async fn is_container_status_running(container_name: &str) -> Result<bool> {
+    is_container_status_running_with(&runner::system(), container_name).await
+}
+
+pub async fn is_container_status_running_with(
+    runner: &Arc<dyn CommandRunner>,
+    container_name: &str,
+) -> Result<bool> {
     print!("💬 Check if container {0} is running", container_name);
     let check_args = vec![
         "container",
@@ -54,7 +92,9 @@ pub async fn is_container_status_running(container_name: &str) -> Result<bool> {
         container_name,
     ];
     let result = CommandBuilder::new()
+        .runner(runner.clone())
         .cmd("docker", &check_args)
+        .read_only()
         .silent()
         .ignore_failures()
         .run()
@@ -70,12 +110,21 @@ pub async fn is_container_status_running(container_name: &str) -> Result<bool> {
 }
 
 pub async fn kill_container(container_name: &str) -> Result<()> {
+    kill_container_with(&runner::system(), container_name).await
+}
+
+pub async fn kill_container_with(
+    runner: &Arc<dyn CommandRunner>,
+    container_name: &str,
+) -> Result<()> {
     let _ = CommandBuilder::new()
+        .runner(runner.clone())
         .cmd("docker", &["kill", container_name])
         .ignore_failures()
         .run()
         .await?;
     let _ = CommandBuilder::new()
+        .runner(runner.clone())
         .cmd("docker", &["rm", container_name])
         .ignore_failures()
         .run()
@@ -0,0 +1,186 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use crate::{redact, utils};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// How many lines of stderr we keep in an error.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Why a command failed. Functions returning `anyhow::Result` wrap this, so get at it with
+/// `err.downcast_ref::<CommandError>()`. Commands are rendered with secrets masked, and
+/// stderr is redacted.
+#[derive(Debug)]
+pub enum CommandError {
+    /// We couldn't start the command - typically because the binary doesn't exist.
+    Spawn {
+        command: String,
+        cwd: Option<String>,
+        source: io::Error,
+    },
+    /// The command exited with a non-zero status.
+    Exit {
+        command: String,
+        cwd: Option<String>,
+        code: i32,
+        stderr_tail: String,
+    },
+    /// The command was killed by a signal that we didn't send.
+    Signal {
+        command: String,
+        cwd: Option<String>,
+        signal: i32,
+        stderr_tail: String,
+    },
+    /// We gave up waiting for the command and killed it.
+    Timeout {
+        command: String,
+        cwd: Option<String>,
+        timeout: Duration,
+        stderr_tail: String,
+    },
+}
+
+impl CommandError {
+    pub(crate) fn spawn(cmd: &CommandBuilder, source: io::Error) -> Self {
+        CommandError::Spawn {
+            command: render(cmd),
+            cwd: cmd.get_cwd().map(|x| x.to_string()),
+            source,
+        }
+    }
+
+    /// The error for a command which produced `output`, which was not successful.
+    pub(crate) fn from_output(cmd: &CommandBuilder, output: &CommandOutput) -> Self {
+        Self::failed(
+            render(cmd),
+            cmd.get_cwd().map(|x| x.to_string()),
+            cmd.get_timeout().unwrap_or_default(),
+            output,
+            &cmd.secrets(),
+        )
+    }
+
+    /// The error for `command`, already rendered, which produced `output`. `timeout` is
+    /// what it was allowed, if it timed out.
+    pub(crate) fn failed(
+        command: String,
+        cwd: Option<String>,
+        timeout: Duration,
+        output: &CommandOutput,
+        secrets: &[String],
+    ) -> Self {
+        let stderr_tail = stderr_tail(&output.stderr, secrets);
+        if output.timed_out {
+            CommandError::Timeout {
+                command,
+                cwd,
+                timeout,
+                stderr_tail,
+            }
+        } else if let Some(signal) = output.signal {
+            CommandError::Signal {
+                command,
+                cwd,
+                signal,
+                stderr_tail,
+            }
+        } else {
+            CommandError::Exit {
+                command,
+                cwd,
+                code: output.status_code,
+                stderr_tail,
+            }
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        match self {
+            CommandError::Spawn { command, .. }
+            | CommandError::Exit { command, .. }
+            | CommandError::Signal { command, .. }
+            | CommandError::Timeout { command, .. } => command,
+        }
+    }
+
+    pub fn cwd(&self) -> Option<&str> {
+        match self {
+            CommandError::Spawn { cwd, .. }
+            | CommandError::Exit { cwd, .. }
+            | CommandError::Signal { cwd, .. }
+            | CommandError::Timeout { cwd, .. } => cwd.as_deref(),
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            CommandError::Exit { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            CommandError::Signal { signal, .. } => Some(*signal),
+            _ => None,
+        }
+    }
+
+    pub fn stderr_tail(&self) -> &str {
+        match self {
+            CommandError::Spawn { .. } => "",
+            CommandError::Exit { stderr_tail, .. }
+            | CommandError::Signal { stderr_tail, .. }
+            | CommandError::Timeout { stderr_tail, .. } => stderr_tail,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let place = self.cwd().map(|x| format!(" in {x}")).unwrap_or_default();
+        match self {
+            CommandError::Spawn {
+                command, source, ..
+            } => write!(f, "Cannot run {command}{place} - {source}")?,
+            CommandError::Exit { command, code, .. } => {
+                write!(f, "Command failed - {code}: {command}{place}")?
+            }
+            CommandError::Signal {
+                command, signal, ..
+            } => write!(f, "Command killed by signal {signal}: {command}{place}")?,
+            CommandError::Timeout {
+                command, timeout, ..
+            } => write!(f, "Command timed out after {timeout:?}: {command}{place}")?,
+        }
+        let tail = self.stderr_tail();
+        if !tail.is_empty() {
+            write!(f, "\n{tail}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn render(cmd: &CommandBuilder) -> String {
+    cmd.describe_command_line()
+        .map(|x| x.trim_end().to_string())
+        .unwrap_or_else(|_| "<no command>".to_string())
+}
+
+/// The last few lines of `stderr`, redacted.
+fn stderr_tail(stderr: &[u8], secrets: &[String]) -> String {
+    let text = redact::redact_with(&utils::string_or_empty_from_u8(stderr), secrets);
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    let skip = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[skip..].join("\n")
+}
@@ -1,12 +1,28 @@
+pub mod audit;
 pub mod bq;
+pub mod capture;
 pub mod commands;
 pub mod containers;
+pub mod errors;
 pub mod filters;
+pub mod limits;
+pub mod manifest;
+pub mod mode;
 pub mod network;
+pub mod packages;
+pub mod parallel;
+pub mod pipeline;
 pub mod process;
+pub mod pty;
 pub mod queries;
+pub mod redact;
+pub mod registry;
 pub mod repo;
+pub mod runner;
 pub mod script;
 pub mod security;
+pub mod supervisor;
+pub mod target;
 pub mod utils;
+pub mod watchers;
 pub mod yaml;
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::os::fd::{FromRawFd as _, OwnedFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::time;
+
+/// I/O scheduling class and priority, as for ionice(1). Priorities run from 0 (highest)
+/// to 7 (lowest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoNice {
+    RealTime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+/// Limits to apply to a command before it starts. Sizes are in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub address_space: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+    pub open_files: Option<u64>,
+    /// Largest core dump; Some(0) disables them.
+    pub core_dump: Option<u64>,
+    /// Niceness, -20 (greedy) to 19 (nice).
+    pub nice: Option<i32>,
+    pub ionice: Option<IoNice>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the limits to the current process. Called between fork() and exec(), so
+    /// this must stick to async-signal-safe calls.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        set_rlimit(libc::RLIMIT_AS, self.address_space)?;
+        set_rlimit(libc::RLIMIT_CPU, self.cpu_seconds)?;
+        set_rlimit(libc::RLIMIT_NOFILE, self.open_files)?;
+        set_rlimit(libc::RLIMIT_CORE, self.core_dump)?;
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(ionice) = self.ionice {
+            set_ionice(ionice)?;
+        }
+        Ok(())
+    }
+}
+
+fn set_rlimit(resource: libc::__rlimit_resource_t, value: Option<u64>) -> io::Result<()> {
+    if let Some(value) = value {
+        let limit = libc::rlimit {
+            rlim_cur: value,
+            rlim_max: value,
+        };
+        if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_ionice(ionice: IoNice) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let (class, data) = match ionice {
+        IoNice::RealTime(prio) => (1, prio),
+        IoNice::BestEffort(prio) => (2, prio),
+        IoNice::Idle => (3, 0),
+    };
+    let ioprio = (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(data.min(7));
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// What a command cost.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub max_rss_kb: u64,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub wall_time: Duration,
+}
+
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage, wall_time: Duration) -> Self {
+        let duration = |tv: &libc::timeval| {
+            Duration::from_secs(u64::try_from(tv.tv_sec).unwrap_or_default())
+                + Duration::from_micros(u64::try_from(tv.tv_usec).unwrap_or_default())
+        };
+        Self {
+            max_rss_kb: u64::try_from(usage.ru_maxrss).unwrap_or_default(),
+            user_time: duration(&usage.ru_utime),
+            system_time: duration(&usage.ru_stime),
+            wall_time,
+        }
+    }
+}
+
+/// Wait until `pid`, a child of ours, exits and return what it cost, leaving it to be
+/// reaped by whoever owns it. `started` is when it was started, for the wall time. We
+/// learn that it has exited from a pidfd, or by polling on kernels without them, so no
+/// thread is tied up while we wait.
+pub(crate) async fn wait_for_exit(
+    pid: i32,
+    started: std::time::Instant,
+) -> io::Result<ResourceUsage> {
+    // Safe: an OwnedFd keeps its descriptor open, and the same, until it is dropped.
+    let exited = open_pidfd(pid)
+        .and_then(|fd| unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }.ok());
+    loop {
+        if let Some(usage) = exited_usage(pid, started)? {
+            return Ok(usage);
+        }
+        match &exited {
+            Some(fd) => fd.readable().await?.clear_ready(),
+            None => time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+/// A descriptor which becomes readable when `pid` exits, if the kernel can give us one.
+fn open_pidfd(pid: i32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    // Safe: the kernel just gave us this descriptor, and nobody else has it.
+    i32::try_from(fd)
+        .ok()
+        .filter(|x| *x >= 0)
+        .map(|x| unsafe { OwnedFd::from_raw_fd(x) })
+}
+
+/// What `pid` cost, if it has exited. It is not reaped: the raw `waitid` will fill in
+/// the usage of a zombie with `WNOWAIT`, which `wait4` can't do.
+fn exited_usage(pid: i32, started: std::time::Instant) -> io::Result<Option<ResourceUsage>> {
+    // Safe: siginfo_t and rusage are plain old data.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    loop {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::c_long::from(libc::P_PID),
+                libc::c_long::from(pid),
+                &mut info as *mut libc::siginfo_t,
+                libc::c_long::from(options),
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if result == 0 {
+            // Safe: the kernel zeroes si_pid when there is nothing to report.
+            return Ok((unsafe { info.si_pid() } != 0)
+                .then(|| ResourceUsage::from_rusage(&usage, started.elapsed())));
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
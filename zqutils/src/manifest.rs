@@ -0,0 +1,350 @@
+use crate::script::{Context, StepStatus};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// The keys a step may have, other than its action.
+const STEP_KEYS: [&str; 2] = ["name", "when"];
+const ACTIONS: [&str; 6] = ["keyring", "packages", "bashrc", "path", "env", "shell"];
+
+/// A machine setup, as YAML:
+///
+/// ```yaml
+/// name: workstation
+/// steps:
+///   - keyring: { name: docker.gpg, url: https://download.docker.com/linux/ubuntu/gpg }
+///   - packages: [git, build-essential]
+///     when: { os: { ID: ubuntu } }
+///   - bashrc: { id: nvm, lines: ['export NVM_DIR="$HOME/.nvm"'] }
+///   - path: /usr/local/go/bin
+///     when: { arch: x86_64 }
+///   - env: { GOPATH: /home/me/go }
+///   - name: Install rust
+///     shell: curl https://sh.rustup.rs -sSf | sh -s -- -y
+/// ```
+///
+/// Steps run in order; `path` and `env` apply to the commands run by the steps after
+/// them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: Option<String>,
+    pub steps: Vec<Step>,
+    /// Where we loaded it from, for messages.
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// What to call the step in messages, instead of describing its action.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<Condition>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Install an apt keyring.
+    Keyring { name: String, url: String },
+    /// Install packages, by their Debian names.
+    Packages(Vec<String>),
+    /// Maintain a block of lines in ~/.bashrc.
+    Bashrc { id: String, lines: Vec<String> },
+    /// Add a directory to the end of PATH.
+    Path(String),
+    /// Set environment variables.
+    Env(BTreeMap<String, String>),
+    /// Run a bash command.
+    Shell(String),
+}
+
+/// When to run a step. Every condition given must hold.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    /// As reported by `arch`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    /// Values from /etc/os-release, eg. `ID: ubuntu`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub os: BTreeMap<String, String>,
+}
+
+impl Condition {
+    /// Why the condition doesn't hold in `ctx`, if it doesn't.
+    pub fn unmet(&self, ctx: &Context) -> Option<String> {
+        if let Some(arch) = &self.arch {
+            if *arch != ctx.arch {
+                return Some(format!("arch is {0}, not {arch}", ctx.arch));
+            }
+        }
+        for (key, want) in &self.os {
+            // os-release values may or may not be quoted.
+            let have = ctx.os_params.get(key).map(|x| x.trim_matches('"'));
+            if have != Some(want.as_str()) {
+                return Some(format!("{key} is {0}, not {want}", have.unwrap_or("unset")));
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Keyring { name, url } => write!(f, "keyring {name} from {url}"),
+            Action::Packages(pkgs) => write!(f, "packages {0}", pkgs.join(" ")),
+            Action::Bashrc { id, .. } => write!(f, "bashrc block {id}"),
+            Action::Path(dir) => write!(f, "path {dir}"),
+            Action::Env(vars) => {
+                let names: Vec<&str> = vars.keys().map(|x| x.as_str()).collect();
+                write!(f, "env {0}", names.join(" "))
+            }
+            Action::Shell(cmd) => write!(f, "shell {cmd}"),
+        }
+    }
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.action.to_string())
+    }
+
+    /// Everything wrong with this step.
+    fn problems(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        match &self.action {
+            Action::Keyring { name, url } => {
+                if name.is_empty() || name.contains('/') {
+                    result.push(format!("keyring name '{name}' must be a plain file name"));
+                }
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    result.push(format!("keyring url '{url}' is not an http(s) URL"));
+                }
+            }
+            Action::Packages(pkgs) => {
+                if pkgs.is_empty() {
+                    result.push("no packages listed".to_string());
+                }
+                for pkg in pkgs {
+                    if pkg.is_empty() || pkg.starts_with('-') || pkg.contains(char::is_whitespace) {
+                        result.push(format!("'{pkg}' is not a package name"));
+                    }
+                }
+            }
+            Action::Bashrc { id, .. } => {
+                if id.is_empty() || id.contains('\n') {
+                    result.push(format!("bashrc id '{id}' must be a non-empty single line"));
+                }
+            }
+            Action::Path(dir) => {
+                if dir.is_empty() || dir.contains(':') {
+                    result.push(format!("path '{dir}' must be a single directory"));
+                }
+            }
+            Action::Env(vars) => {
+                for name in vars.keys() {
+                    let valid = name.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+                        && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_');
+                    if !valid {
+                        result.push(format!("'{name}' is not a variable name"));
+                    }
+                }
+            }
+            Action::Shell(cmd) => {
+                if cmd.trim().is_empty() {
+                    result.push("shell command is empty".to_string());
+                }
+            }
+        }
+        result
+    }
+}
+
+/// What apply() would do with a step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedStep {
+    /// From 1, as in messages.
+    pub number: usize,
+    pub description: String,
+    /// Why we would skip the step, if we would.
+    pub skip_reason: Option<String>,
+}
+
+impl fmt::Display for PlannedStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.skip_reason {
+            None => write!(f, "{0:>3}. {1}", self.number, self.description),
+            Some(reason) => write!(
+                f,
+                "{0:>3}. {1} (skipped: {reason})",
+                self.number, self.description
+            ),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Cannot read manifest {0} - {e}", path.display()))?;
+        Self::from_yaml(&contents, &path.display().to_string())
+    }
+
+    /// Parse and validate a manifest; `source` says where it came from, for messages.
+    pub fn from_yaml(yaml: &str, source: &str) -> Result<Self> {
+        let doc: Value = serde_yaml::from_str(yaml)
+            .map_err(|e| anyhow!("Cannot parse manifest {source} - {e}"))?;
+        let doc = doc
+            .as_mapping()
+            .ok_or(anyhow!("Manifest {source} is not a mapping"))?;
+        let mut errors = Vec::new();
+        for key in doc.keys() {
+            if !matches!(key.as_str(), Some("name" | "steps")) {
+                errors.push(format!("unknown key {0}", describe_value(key)));
+            }
+        }
+        let name = match doc.get("name") {
+            None => None,
+            Some(Value::String(x)) => Some(x.clone()),
+            Some(other) => {
+                errors.push(format!(
+                    "name should be a string, not {0}",
+                    describe_value(other)
+                ));
+                None
+            }
+        };
+        let raw_steps = match doc.get("steps") {
+            None => Vec::new(),
+            Some(Value::Sequence(x)) => x.clone(),
+            Some(_) => {
+                errors.push("steps should be a list".to_string());
+                Vec::new()
+            }
+        };
+        let mut steps = Vec::new();
+        for (idx, raw) in raw_steps.into_iter().enumerate() {
+            let step = parse_step(raw).and_then(|step| match step.problems().as_slice() {
+                [] => Ok(step),
+                problems => Err(format!("{0} - {1}", step.describe(), problems.join("; "))),
+            });
+            match step {
+                Ok(step) => steps.push(step),
+                Err(e) => errors.push(format!("step {0}: {e}", idx + 1)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Invalid manifest {source}:\n  {0}",
+                errors.join("\n  ")
+            ));
+        }
+        Ok(Self {
+            name,
+            steps,
+            source: source.to_string(),
+        })
+    }
+
+    /// What apply() would do in `ctx`.
+    pub fn plan(&self, ctx: &Context) -> Vec<PlannedStep> {
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(idx, step)| PlannedStep {
+                number: idx + 1,
+                description: step.describe(),
+                skip_reason: step.when.as_ref().and_then(|x| x.unmet(ctx)),
+            })
+            .collect()
+    }
+
+    /// Run the steps whose conditions hold, in order, stopping at the first failure.
+    pub async fn apply(&self, ctx: &mut Context) -> Result<()> {
+        for planned in self.plan(ctx) {
+            println!("📋 {planned}");
+            if let Some(reason) = planned.skip_reason {
+                ctx.record_step(&planned.description, StepStatus::Skipped(reason));
+                continue;
+            }
+            let step = &self.steps[planned.number - 1];
+            apply_action(&step.action, ctx).await.map_err(|e| {
+                anyhow!(
+                    "Manifest {0}, step {1} ({2}) failed - {e}",
+                    self.source,
+                    planned.number,
+                    planned.description
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_value(val: &Value) -> String {
+    serde_yaml::to_string(val)
+        .map(|x| x.trim_end().to_string())
+        .unwrap_or_default()
+}
+
+fn parse_step(raw: Value) -> std::result::Result<Step, String> {
+    let map = raw.as_mapping().ok_or(format!(
+        "should be a mapping, not {0}",
+        describe_value(&raw)
+    ))?;
+    let mut actions = Vec::new();
+    for key in map.keys() {
+        match key.as_str() {
+            Some(x) if STEP_KEYS.contains(&x) => (),
+            Some(x) if ACTIONS.contains(&x) => actions.push(x),
+            _ => {
+                return Err(format!(
+                    "unknown key {0} - expected one of {1}, {2}",
+                    describe_value(key),
+                    ACTIONS.join(", "),
+                    STEP_KEYS.join(", ")
+                ))
+            }
+        }
+    }
+    match actions.as_slice() {
+        [_] => serde_yaml::from_value(raw.clone()).map_err(|e| e.to_string()),
+        [] => Err(format!(
+            "no action - expected one of {0}",
+            ACTIONS.join(", ")
+        )),
+        many => Err(format!("more than one action ({0})", many.join(", "))),
+    }
+}
+
+async fn apply_action(action: &Action, ctx: &mut Context) -> Result<()> {
+    match action {
+        Action::Keyring { name, url } => ctx.install_keyring(url, name).await,
+        Action::Packages(pkgs) => {
+            let pkgs: Vec<&str> = pkgs.iter().map(|x| x.as_str()).collect();
+            ctx.install_packages(&pkgs).await
+        }
+        Action::Bashrc { id, lines } => {
+            ctx.append_bashrc(id, &lines.iter().map(|x| x.as_str()).collect())
+                .await
+        }
+        Action::Path(dir) => {
+            ctx.add_to_path(dir);
+            Ok(())
+        }
+        Action::Env(vars) => {
+            for (name, val) in vars {
+                ctx.add_to_env(name, val);
+            }
+            Ok(())
+        }
+        Action::Shell(cmd) => ctx.shell(cmd).await,
+    }
+}
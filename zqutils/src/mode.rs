@@ -0,0 +1,99 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use crate::runner::FakeRunner;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The mode of every command without a mode of its own, outside with_mode().
+static GLOBAL_MODE: RwLock<ExecutionMode> = RwLock::new(ExecutionMode::Execute);
+
+tokio::task_local! {
+    static SCOPED_MODE: ExecutionMode;
+}
+
+/// What running a command actually does.
+#[derive(Debug, Clone, Default)]
+pub enum ExecutionMode {
+    /// Run it.
+    #[default]
+    Execute,
+    /// Print it and pretend it succeeded, with no output. Read-only commands still run.
+    DryRun,
+    /// Run it, and remember what it did so that we can replay it later.
+    Record(Arc<Recording>),
+}
+
+impl ExecutionMode {
+    /// Set the mode for the whole process.
+    pub fn install_global(mode: ExecutionMode) {
+        if let Ok(mut global) = GLOBAL_MODE.write() {
+            *global = mode;
+        }
+    }
+
+    /// The mode in force here: that of the enclosing with_mode(), if any, or else the
+    /// global one.
+    pub fn current() -> ExecutionMode {
+        SCOPED_MODE
+            .try_with(|x| x.clone())
+            .unwrap_or_else(|_| GLOBAL_MODE.read().map(|x| x.clone()).unwrap_or_default())
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self, ExecutionMode::DryRun)
+    }
+}
+
+/// Run `future` with commands in `mode`. The mode is not inherited by tasks the future
+/// spawns.
+pub async fn with_mode<F: Future>(mode: ExecutionMode, future: F) -> F::Output {
+    SCOPED_MODE.scope(mode, future).await
+}
+
+/// A command we ran in record mode, and what came of it.
+#[derive(Debug, Clone)]
+pub struct RecordedCommand {
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<String>,
+    pub output: CommandOutput,
+}
+
+/// The commands run in record mode, in the order they finished.
+#[derive(Debug, Default)]
+pub struct Recording {
+    commands: Mutex<Vec<RecordedCommand>>,
+}
+
+impl Recording {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub(crate) fn record(&self, cmd: &CommandBuilder, output: &CommandOutput) {
+        if let (Some(name), Ok(mut commands)) = (cmd.get_cmd(), self.commands.lock()) {
+            commands.push(RecordedCommand {
+                cmd: name.to_string(),
+                args: cmd.get_args().to_vec(),
+                env: cmd.get_env().cloned().unwrap_or_default(),
+                cwd: cmd.get_cwd().map(|x| x.to_string()),
+                output: output.clone(),
+            });
+        }
+    }
+
+    pub fn commands(&self) -> Vec<RecordedCommand> {
+        self.commands.lock().map(|x| x.clone()).unwrap_or_default()
+    }
+
+    /// A runner which answers the recorded commands with their recorded output, in order.
+    pub fn replay(&self) -> FakeRunner {
+        let runner = FakeRunner::new();
+        for recorded in self.commands() {
+            let args: Vec<&str> = recorded.args.iter().map(|x| x.as_str()).collect();
+            runner.respond(&recorded.cmd, &args, recorded.output);
+        }
+        runner
+    }
+}
@@ -0,0 +1,265 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A distribution's package manager. Package names given to zqutils are Debian's;
+/// PackageNames translates them for the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Apk,
+    Pacman,
+    Zypper,
+}
+
+/// Succeeds if every package named in its arguments is installed.
+const APT_PROBE: &str = "status=$(dpkg-query -W -f='${db:Status-Status}\\n' \"$@\") && \
+     ! printf '%s\\n' \"$status\" | grep -qvx installed";
+
+impl fmt::Display for PackageManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PackageManager::Apt => "apt",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Apk => "apk",
+            PackageManager::Pacman => "pacman",
+            PackageManager::Zypper => "zypper",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl PackageManager {
+    /// The package manager for a distribution, from the `ID` or, failing that, `ID_LIKE`
+    /// in its /etc/os-release.
+    pub fn detect(os_params: &HashMap<String, String>) -> Result<Self> {
+        let value = |key: &str| {
+            os_params
+                .get(key)
+                .map(|x| x.trim_matches('"').to_string())
+                .unwrap_or_default()
+        };
+        let id = value("ID");
+        let id_like = value("ID_LIKE");
+        std::iter::once(id.as_str())
+            .chain(id_like.split_whitespace())
+            .find_map(Self::for_distribution)
+            .ok_or(anyhow!(
+                "Don't know the package manager for ID={id} ID_LIKE={id_like}"
+            ))
+    }
+
+    fn for_distribution(id: &str) -> Option<Self> {
+        match id {
+            "debian" | "ubuntu" => Some(PackageManager::Apt),
+            "fedora" | "rhel" | "centos" | "rocky" | "almalinux" | "amzn" => {
+                Some(PackageManager::Dnf)
+            }
+            "alpine" => Some(PackageManager::Apk),
+            "arch" | "manjaro" | "endeavouros" => Some(PackageManager::Pacman),
+            "opensuse" | "opensuse-leap" | "opensuse-tumbleweed" | "sles" | "suse" => {
+                Some(PackageManager::Zypper)
+            }
+            _ => None,
+        }
+    }
+
+    /// The beginning of every command line, with whatever it takes to stop the package
+    /// manager asking questions.
+    fn base(&self) -> Vec<&'static str> {
+        match self {
+            PackageManager::Apt => vec!["apt-get", "-q", "-y"],
+            PackageManager::Dnf => vec!["dnf", "-y"],
+            PackageManager::Apk => vec!["apk", "--no-progress"],
+            PackageManager::Pacman => vec!["pacman", "--noconfirm"],
+            PackageManager::Zypper => vec!["zypper", "--non-interactive"],
+        }
+    }
+
+    /// Environment the package manager needs to run unattended.
+    pub fn env(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            PackageManager::Apt => vec![("DEBIAN_FRONTEND", "noninteractive")],
+            _ => Vec::new(),
+        }
+    }
+
+    fn command(&self, words: &[&str], pkgs: &[String]) -> Vec<String> {
+        self.base()
+            .into_iter()
+            .chain(words.iter().copied())
+            .map(|x| x.to_string())
+            .chain(pkgs.iter().cloned())
+            .collect()
+    }
+
+    /// Refresh the package lists. Pacman upgrades everything too: installing from lists
+    /// newer than what is installed is a partial upgrade, which Arch doesn't support.
+    pub fn update_command(&self) -> Vec<String> {
+        match self {
+            PackageManager::Apt | PackageManager::Apk => self.command(&["update"], &[]),
+            PackageManager::Dnf => self.command(&["makecache"], &[]),
+            PackageManager::Pacman => self.command(&["-Syu"], &[]),
+            PackageManager::Zypper => self.command(&["refresh"], &[]),
+        }
+    }
+
+    /// Upgrade everything installed.
+    pub fn upgrade_command(&self) -> Vec<String> {
+        match self {
+            PackageManager::Apt => self.command(&["dist-upgrade"], &[]),
+            PackageManager::Dnf | PackageManager::Apk => self.command(&["upgrade"], &[]),
+            PackageManager::Pacman => self.command(&["-Syu"], &[]),
+            PackageManager::Zypper => self.command(&["update"], &[]),
+        }
+    }
+
+    /// Install `pkgs`, which should already be in this package manager's names.
+    pub fn install_command(&self, pkgs: &[String]) -> Vec<String> {
+        match self {
+            PackageManager::Apt | PackageManager::Dnf | PackageManager::Zypper => {
+                self.command(&["install"], pkgs)
+            }
+            PackageManager::Apk => self.command(&["add"], pkgs),
+            PackageManager::Pacman => self.command(&["-S", "--needed"], pkgs),
+        }
+    }
+
+    pub fn remove_command(&self, pkgs: &[String]) -> Vec<String> {
+        match self {
+            PackageManager::Apt | PackageManager::Dnf | PackageManager::Zypper => {
+                self.command(&["remove"], pkgs)
+            }
+            PackageManager::Apk => self.command(&["del"], pkgs),
+            PackageManager::Pacman => self.command(&["-R"], pkgs),
+        }
+    }
+
+    /// A command which succeeds only if all of `pkgs` are installed. No root needed.
+    pub fn installed_probe(&self, pkgs: &[String]) -> CommandBuilder {
+        let (name, args): (&str, &[&str]) = match self {
+            // dpkg knows about packages which were removed but not purged, so check that
+            // every one of them is actually installed.
+            PackageManager::Apt => ("sh", &["-c", APT_PROBE, "sh"]),
+            PackageManager::Dnf | PackageManager::Zypper => ("rpm", &["-q"]),
+            PackageManager::Apk => ("apk", &["info", "-e"]),
+            PackageManager::Pacman => ("pacman", &["-Q"]),
+        };
+        let mut args: Vec<&str> = args.to_vec();
+        args.extend(pkgs.iter().map(|x| x.as_str()));
+        let mut result = CommandBuilder::new();
+        result.cmd(name, &args);
+        result
+    }
+
+    /// A command which asks what version of `pkg` is installed. Pass its output to
+    /// parse_version().
+    pub fn version_query(&self, pkg: &str) -> CommandBuilder {
+        let mut result = CommandBuilder::new();
+        match self {
+            PackageManager::Apt => result.cmd(
+                "dpkg-query",
+                &["-W", "-f=${db:Status-Status} ${Version}", pkg],
+            ),
+            PackageManager::Dnf | PackageManager::Zypper => {
+                result.cmd("rpm", &["-q", "--qf", "%{VERSION}-%{RELEASE}", pkg])
+            }
+            PackageManager::Apk => result.cmd("apk", &["list", "--installed", pkg]),
+            PackageManager::Pacman => result.cmd("pacman", &["-Q", pkg]),
+        };
+        result
+    }
+
+    /// The installed version of `pkg`, from the output of version_query(); None if it
+    /// isn't installed.
+    pub fn parse_version(&self, pkg: &str, output: &CommandOutput) -> Option<String> {
+        if !output.success {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.lines().find(|x| !x.trim().is_empty())?.trim();
+        let version = match self {
+            // Known packages that were removed are still listed, as "deinstall".
+            PackageManager::Apt => line.strip_prefix("installed ")?,
+            PackageManager::Dnf | PackageManager::Zypper => line,
+            // pkg-1.2.3-r0 x86_64 {origin} (licence) [installed]
+            PackageManager::Apk => line
+                .split_whitespace()
+                .next()?
+                .strip_prefix(pkg)?
+                .strip_prefix('-')?,
+            // pkg 1.2.3-1
+            PackageManager::Pacman => line.strip_prefix(pkg)?.trim(),
+        };
+        Some(version.to_string()).filter(|x| !x.is_empty())
+    }
+}
+
+/// Translates Debian package names to those of other distributions.
+#[derive(Debug, Clone)]
+pub struct PackageNames {
+    names: HashMap<(String, PackageManager), Vec<String>>,
+}
+
+impl Default for PackageNames {
+    /// Some common packages whose names differ.
+    fn default() -> Self {
+        let mut result = Self {
+            names: HashMap::new(),
+        };
+        let dev_tools = ["gcc", "gcc-c++", "make"];
+        result
+            .map("build-essential", PackageManager::Dnf, &dev_tools)
+            .map("build-essential", PackageManager::Zypper, &dev_tools)
+            .map("build-essential", PackageManager::Apk, &["build-base"])
+            .map("build-essential", PackageManager::Pacman, &["base-devel"])
+            .map("pkg-config", PackageManager::Dnf, &["pkgconf-pkg-config"])
+            .map("pkg-config", PackageManager::Apk, &["pkgconf"])
+            .map("pkg-config", PackageManager::Pacman, &["pkgconf"])
+            .map("libssl-dev", PackageManager::Dnf, &["openssl-devel"])
+            .map("libssl-dev", PackageManager::Zypper, &["libopenssl-devel"])
+            .map("libssl-dev", PackageManager::Apk, &["openssl-dev"])
+            .map("libssl-dev", PackageManager::Pacman, &["openssl"])
+            .map("python3-pip", PackageManager::Apk, &["py3-pip"])
+            .map("python3-pip", PackageManager::Pacman, &["python-pip"]);
+        result
+    }
+}
+
+impl PackageNames {
+    /// No translations at all.
+    pub fn empty() -> Self {
+        Self {
+            names: HashMap::new(),
+        }
+    }
+
+    /// On `manager`, install `to` for Debian's `name`. An empty `to` means there's
+    /// nothing to install.
+    pub fn map(&mut self, name: &str, manager: PackageManager, to: &[&str]) -> &mut Self {
+        self.names.insert(
+            (name.to_string(), manager),
+            to.iter().map(|x| x.to_string()).collect(),
+        );
+        self
+    }
+
+    /// `pkgs`, as `manager` calls them. Names with no translation are used as they are.
+    pub fn resolve(&self, manager: PackageManager, pkgs: &[&str]) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        for pkg in pkgs {
+            let names = match self.names.get(&(pkg.to_string(), manager)) {
+                Some(names) => names.clone(),
+                None => vec![pkg.to_string()],
+            };
+            for name in names {
+                if !result.contains(&name) {
+                    result.push(name);
+                }
+            }
+        }
+        result
+    }
+}
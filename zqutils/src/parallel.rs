@@ -0,0 +1,236 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use crate::errors::CommandError;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// What to do when one of a set of parallel commands fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Start no more commands; those already running are left to finish.
+    FailFast,
+    /// Run everything regardless.
+    CollectAll,
+}
+
+/// How one of a set of parallel commands went.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum JobResult {
+    /// It ran - though it may not have succeeded.
+    Finished(CommandOutput),
+    /// We couldn't run it.
+    Error(anyhow::Error),
+    /// We didn't run it, because something else failed first.
+    Skipped,
+}
+
+impl JobResult {
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match self {
+            JobResult::Finished(output) => Some(output),
+            _ => None,
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.output().is_some_and(|x| x.success)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParallelSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for ParallelSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{0} succeeded, {1} failed, {2} skipped in {3:.1}s",
+            self.succeeded,
+            self.failed,
+            self.skipped,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParallelOutput {
+    /// One per command, in the order they were added.
+    pub labels: Vec<String>,
+    pub results: Vec<JobResult>,
+    pub summary: ParallelSummary,
+}
+
+impl ParallelOutput {
+    /// The labels of the commands which failed or could not be run.
+    pub fn failures(&self) -> Vec<&str> {
+        self.labels
+            .iter()
+            .zip(&self.results)
+            .filter(|(_, result)| !matches!(result, JobResult::Skipped) && !result.succeeded())
+            .map(|(label, _)| label.as_str())
+            .collect()
+    }
+}
+
+/// Run many independent commands, at most `concurrency` at a time.
+#[derive(Debug, Clone)]
+pub struct Parallel {
+    commands: Vec<(String, CommandBuilder)>,
+    concurrency: usize,
+    policy: FailurePolicy,
+    throw_on_failure: bool,
+    /// Should we log the output, or return it?
+    logged: bool,
+}
+
+impl Parallel {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            commands: Vec::new(),
+            concurrency: concurrency.max(1),
+            policy: FailurePolicy::CollectAll,
+            throw_on_failure: true,
+            logged: false,
+        }
+    }
+
+    /// Add a command. If we are logging output, its lines are prefixed with `label`.
+    pub fn add(&mut self, label: &str, cmd: &CommandBuilder) -> &mut Self {
+        self.commands.push((label.to_string(), cmd.clone()));
+        self
+    }
+
+    pub fn fail_fast(&mut self) -> &mut Self {
+        self.policy = FailurePolicy::FailFast;
+        self
+    }
+
+    pub fn collect_all(&mut self) -> &mut Self {
+        self.policy = FailurePolicy::CollectAll;
+        self
+    }
+
+    pub fn log_output(&mut self) -> &mut Self {
+        self.logged = true;
+        self
+    }
+
+    pub fn ignore_failures(&mut self) -> &mut Self {
+        self.throw_on_failure = false;
+        self
+    }
+
+    pub fn throw_on_failure(&mut self) -> &mut Self {
+        self.throw_on_failure = true;
+        self
+    }
+
+    /// Run the commands. Failure, if we are throwing on it, is reported once they have all
+    /// finished (or been skipped).
+    pub async fn run(&self) -> Result<ParallelOutput> {
+        let started = Instant::now();
+        let width = self
+            .commands
+            .iter()
+            .map(|(label, _)| label.len())
+            .max()
+            .unwrap_or(0);
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let failed = Arc::new(AtomicBool::new(false));
+        let mut tasks = JoinSet::new();
+        for (idx, (label, cmd)) in self.commands.iter().enumerate() {
+            let mut cmd = cmd.clone();
+            cmd.label(&format!("{label:<width$}"))
+                .ignore_failures()
+                .inherit_execution_mode();
+            let permits = permits.clone();
+            let failed = failed.clone();
+            let fail_fast = self.policy == FailurePolicy::FailFast;
+            let logged = self.logged;
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                if fail_fast && failed.load(Ordering::SeqCst) {
+                    return (idx, JobResult::Skipped);
+                }
+                let result = if logged {
+                    cmd.run_logged().await
+                } else {
+                    cmd.run_for_output().await
+                };
+                let result = match result {
+                    Ok(output) => JobResult::Finished(output),
+                    Err(e) => JobResult::Error(e),
+                };
+                if !result.succeeded() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                (idx, result)
+            });
+        }
+        let mut results: Vec<Option<JobResult>> = self.commands.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next().await {
+            let (idx, result) = joined?;
+            results[idx] = Some(result);
+        }
+        let results: Vec<JobResult> = results
+            .into_iter()
+            .map(|x| x.unwrap_or(JobResult::Skipped))
+            .collect();
+        let skipped = results
+            .iter()
+            .filter(|x| matches!(x, JobResult::Skipped))
+            .count();
+        let succeeded = results.iter().filter(|x| x.succeeded()).count();
+        let output = ParallelOutput {
+            labels: self.commands.iter().map(|(x, _)| x.clone()).collect(),
+            summary: ParallelSummary {
+                succeeded,
+                failed: results.len() - succeeded - skipped,
+                skipped,
+                duration: started.elapsed(),
+            },
+            results,
+        };
+        if self.logged {
+            println!("\r\n📊 {0}", output.summary);
+        }
+        if self.throw_on_failure && output.summary.failed > 0 {
+            return Err(self.failure(&output));
+        }
+        Ok(output)
+    }
+
+    /// Describe what went wrong, starting with the first failure.
+    fn failure(&self, output: &ParallelOutput) -> anyhow::Error {
+        let first = self
+            .commands
+            .iter()
+            .zip(&output.results)
+            .find_map(|((label, cmd), result)| match result {
+                JobResult::Finished(x) if !x.success => {
+                    Some(format!("{label}: {0}", CommandError::from_output(cmd, x)))
+                }
+                JobResult::Error(e) => Some(format!("{label}: {e}")),
+                _ => None,
+            })
+            .unwrap_or_default();
+        anyhow!(
+            "{0} of {1} commands failed ({2}) - {3}\n{first}",
+            output.summary.failed,
+            self.commands.len(),
+            output.failures().join(", "),
+            output.summary
+        )
+    }
+}
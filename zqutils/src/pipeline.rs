@@ -0,0 +1,286 @@
+use crate::capture::Captured;
+use crate::commands::{self, ChildProcess, CommandBuilder, CommandOutput, EchoOptions, Input};
+use crate::errors::CommandError;
+use crate::watchers::OutputStream;
+use anyhow::{anyhow, Result};
+use colored::Color;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::task::JoinHandle;
+
+/// `a | b | c`, without going through a shell.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<CommandBuilder>,
+    input: Option<Input>,
+    throw_on_failure: bool,
+    display_command: bool,
+    /// Should we log the output, or return it?
+    logged: bool,
+    color: Option<Color>,
+}
+
+pub struct PipelineOutput {
+    /// The pipeline as a whole: the status is that of the last stage to fail (as with
+    /// bash's pipefail), stdout is that of the last stage and stderr that of every stage.
+    pub output: CommandOutput,
+    /// The result of each stage, in order. Only stderr is captured.
+    pub stages: Vec<CommandOutput>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            stages: Vec::new(),
+            input: None,
+            throw_on_failure: true,
+            display_command: true,
+            logged: false,
+            color: None,
+        }
+    }
+
+    /// Add a stage; its stdin will be the stdout of the stage before it. Display, logging
+    /// and failure options on the stage itself are ignored.
+    pub fn stage(&mut self, cmd: &CommandBuilder) -> &mut Self {
+        self.stages.push(cmd.clone());
+        self
+    }
+
+    /// Feed this to the first stage.
+    pub fn input(&mut self, data: &[u8]) -> &mut Self {
+        self.input = Some(Input::Bytes(data.to_vec()));
+        self
+    }
+
+    /// Feed the first stage from the file at `path`.
+    pub fn input_file(&mut self, path: &Path) -> &mut Self {
+        self.input = Some(Input::File(path.to_path_buf()));
+        self
+    }
+
+    pub fn log_output(&mut self) -> &mut Self {
+        self.logged = true;
+        self
+    }
+
+    pub fn color(&mut self, what: Color) -> &mut Self {
+        self.color = Some(what);
+        self
+    }
+
+    pub fn ignore_failures(&mut self) -> &mut Self {
+        self.throw_on_failure = false;
+        self
+    }
+
+    pub fn throw_on_failure(&mut self) -> &mut Self {
+        self.throw_on_failure = true;
+        self
+    }
+
+    pub fn silent(&mut self) -> &mut Self {
+        self.display_command = false;
+        self
+    }
+
+    pub fn describe_command(&self) -> Result<String> {
+        let (first, rest) = self
+            .stages
+            .split_first()
+            .ok_or(anyhow!("Pipeline has no stages"))?;
+        let mut result = first.describe_command()?;
+        for stage in rest {
+            result.push_str(" | ");
+            result.push_str(&stage.describe_command_line()?);
+        }
+        Ok(result)
+    }
+
+    fn echo_options(&self, stream: OutputStream, secrets: &[String]) -> EchoOptions {
+        EchoOptions {
+            stream,
+            prefix: stream.prefix(),
+            color: self.color,
+            capture: true,
+            echo: self.logged,
+            secrets: secrets.to_vec(),
+            hub: None,
+            label: None,
+            limit: None,
+            stop: None,
+        }
+    }
+
+    /// Start stage `idx`, adding it to `children` as soon as it is running, and the task
+    /// reading its stderr to `err_tasks`.
+    fn spawn_stage(
+        &self,
+        idx: usize,
+        stage: &CommandBuilder,
+        previous_output: &mut Option<Stdio>,
+        secrets: &[String],
+        children: &mut Vec<ChildProcess>,
+        err_tasks: &mut Vec<JoinHandle<Captured>>,
+    ) -> Result<()> {
+        let mut cmd = stage.build_command()?;
+        let mut to_write = None;
+        if let Some(prev) = previous_output.take() {
+            cmd.stdin(prev);
+        } else {
+            to_write = commands::prepare_input(&mut cmd, self.input.as_ref())?;
+        }
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        children.push(stage.spawn_command(&mut cmd)?);
+        let proc = children.last_mut().ok_or(anyhow!("Cannot get process"))?;
+        proc.start_input(to_write)?;
+        let err = proc
+            .child
+            .stderr
+            .take()
+            .ok_or(anyhow!("Cannot get process error"))?;
+        let mut err_options = self.echo_options(OutputStream::Stderr, secrets);
+        err_options.stop = Some(proc.stop_signal());
+        err_tasks.push(tokio::spawn(commands::echo_lines(err, err_options)));
+        if idx + 1 < self.stages.len() {
+            let output = proc
+                .child
+                .stdout
+                .take()
+                .ok_or(anyhow!("Cannot get process output"))?;
+            *previous_output = Some(output.try_into()?);
+        }
+        Ok(())
+    }
+
+    /// The pipeline as a line you can paste into a POSIX shell.
+    pub fn to_shell_command(&self) -> Result<String> {
+        if self.stages.is_empty() {
+            return Err(anyhow!("Pipeline has no stages"));
+        }
+        let stages = self
+            .stages
+            .iter()
+            .map(|x| x.to_shell_command())
+            .collect::<Result<Vec<String>>>()?;
+        Ok(stages.join(" | "))
+    }
+
+    pub async fn run(&self) -> Result<PipelineOutput> {
+        if self
+            .stages
+            .iter()
+            .any(|x| x.get_execution_mode().is_dry_run())
+        {
+            println!("{0}  # 🔍 dry run", self.to_shell_command()?);
+            return Ok(PipelineOutput {
+                output: CommandOutput::fake(true),
+                stages: self
+                    .stages
+                    .iter()
+                    .map(|_| CommandOutput::fake(true))
+                    .collect(),
+            });
+        }
+        if self.display_command {
+            println!("{0}", self.describe_command()?);
+        }
+        let secrets: Vec<String> = self.stages.iter().flat_map(|x| x.secrets()).collect();
+        let mut children: Vec<ChildProcess> = Vec::new();
+        let mut err_tasks = Vec::new();
+        let mut previous_output: Option<Stdio> = None;
+        for (idx, stage) in self.stages.iter().enumerate() {
+            let spawned = self.spawn_stage(
+                idx,
+                stage,
+                &mut previous_output,
+                &secrets,
+                &mut children,
+                &mut err_tasks,
+            );
+            if let Err(e) = spawned {
+                // Don't leave the stages we did start running with nobody to wait for them.
+                for proc in &mut children {
+                    let _ = proc.signal(libc::SIGKILL);
+                    let _ = proc.wait_exit().await;
+                }
+                return Err(e);
+            }
+        }
+
+        let last = children
+            .last_mut()
+            .ok_or(anyhow!("Pipeline has no stages"))?;
+        let last_output = last
+            .child
+            .stdout
+            .take()
+            .ok_or(anyhow!("Cannot get process output"))?;
+        let mut out_options = self.echo_options(OutputStream::Stdout, &secrets);
+        out_options.stop = Some(last.stop_signal());
+        let out_task = tokio::spawn(commands::echo_lines(last_output, out_options));
+
+        let mut stages = Vec::new();
+        for ((stage, proc), err_task) in self.stages.iter().zip(&mut children).zip(err_tasks) {
+            let (status, timed_out) = stage.wait_for(proc).await?;
+            proc.finish_input().await?;
+            let stderr = proc.drain(err_task).await?;
+            let result = stage.make_output(
+                status,
+                timed_out,
+                Default::default(),
+                stderr,
+                proc.usage().cloned(),
+            );
+            proc.record_completion(&result);
+            stages.push(result);
+        }
+        let stdout = match children.last() {
+            Some(last) => last.drain(out_task).await?.data,
+            None => Vec::new(),
+        };
+
+        let failed = stages.iter().rev().find(|x| !x.success);
+        let mut output = match failed {
+            Some(stage) => CommandOutput {
+                success: false,
+                status_code: stage.status_code,
+                stdout,
+                stderr: Vec::new(),
+                timed_out: stage.timed_out,
+                signal: stage.signal,
+                ..CommandOutput::fake(false)
+            },
+            None => CommandOutput {
+                stdout,
+                ..CommandOutput::fake(true)
+            },
+        };
+        output.stderr = stages.iter().flat_map(|x| x.stderr.clone()).collect();
+        if self.throw_on_failure && !output.success {
+            let command = self
+                .stages
+                .iter()
+                .map(|x| Ok(x.describe_command_line()?.trim_end().to_string()))
+                .collect::<Result<Vec<String>>>()?
+                .join(" | ");
+            let timeout = self
+                .stages
+                .iter()
+                .zip(&stages)
+                .rev()
+                .find(|(_, x)| !x.success)
+                .and_then(|(stage, _)| stage.get_timeout())
+                .unwrap_or_default();
+            return Err(CommandError::failed(command, None, timeout, &output, &secrets).into());
+        }
+        Ok(PipelineOutput { output, stages })
+    }
+}
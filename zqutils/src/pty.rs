@@ -0,0 +1,347 @@
+use crate::commands::{
+    BoxedReader, ChildProcess, CommandBuilder, CommandOutput, Input, DRAIN_GRACE,
+};
+use crate::watchers::{LineHub, OutputStream};
+use crate::{redact, utils};
+use anyhow::{anyhow, Result};
+use regex::bytes::Regex;
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{FromRawFd as _, OwnedFd};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::process::Command;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// End of file, as typed at a terminal.
+const EOF_CHAR: u8 = 0x04;
+
+/// A pseudo-terminal. The child gets the slave side as its stdin, stdout, stderr and
+/// controlling terminal; we read and write the master side.
+pub(crate) struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    pub(crate) fn open() -> io::Result<Self> {
+        let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC;
+        // Safe: we check every return value, and own the fds we get back.
+        unsafe {
+            let fd = libc::posix_openpt(flags);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = OwnedFd::from_raw_fd(fd);
+            if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            let err = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+            let slave_fd = libc::open(CStr::from_ptr(name.as_ptr()).as_ptr(), flags);
+            if slave_fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let slave = OwnedFd::from_raw_fd(slave_fd);
+            // Some programs won't prompt on a terminal with no size.
+            let size = libc::winsize {
+                ws_row: 24,
+                ws_col: 80,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            libc::ioctl(slave_fd, libc::TIOCSWINSZ, &size);
+            Ok(Self { master, slave })
+        }
+    }
+
+    /// Run `cmd` on this terminal.
+    pub(crate) fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        cmd.stdin(Stdio::from(self.slave.try_clone()?));
+        cmd.stdout(Stdio::from(self.slave.try_clone()?));
+        cmd.stderr(Stdio::from(self.slave.try_clone()?));
+        // Safe: setsid() and ioctl() are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| {
+                // Fails harmlessly if the builder already made us a session leader.
+                libc::setsid();
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// The master side, to read from or write to. Once the child has started, drop the
+    /// Pty so that reads see the end of the output when the child exits.
+    pub(crate) fn master(&self) -> io::Result<tokio::fs::File> {
+        Ok(tokio::fs::File::from_std(std::fs::File::from(
+            self.master.try_clone()?,
+        )))
+    }
+}
+
+/// Spawn `builder`'s command on a new terminal.
+async fn spawn_on_pty(builder: &CommandBuilder) -> Result<(ChildProcess, Pty)> {
+    let pty = Pty::open().map_err(|e| anyhow!("Cannot open a pseudo-terminal - {e}"))?;
+    let mut cmd = builder.make_command()?;
+    pty.attach(&mut cmd)?;
+    let mut proc = builder.spawn_command(&mut cmd)?;
+    proc.session_leader = true;
+    // Again, now that we know it leads a session.
+    builder.track(&proc);
+    Ok((proc, pty))
+}
+
+/// Where input for a terminal comes from.
+async fn open_input(input: Option<&Input>) -> Result<Option<BoxedReader>> {
+    match input {
+        None => Ok(None),
+        Some(Input::Bytes(val)) => Ok(Some(Box::new(std::io::Cursor::new(val.clone())))),
+        Some(Input::File(path)) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .map_err(|e| anyhow!("Cannot open input {0} - {e}", path.display()))?;
+            Ok(Some(Box::new(file)))
+        }
+        Some(Input::Reader(reader)) => Ok(Some(
+            reader
+                .lock()
+                .map_err(|_| anyhow!("Input reader lock is poisoned"))?
+                .take()
+                .ok_or(anyhow!("Input reader has already been used"))?,
+        )),
+    }
+}
+
+/// Terminals end lines with \r\n; we'd rather not.
+fn normalise_newlines(output: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(output.len());
+    for (idx, byte) in output.iter().enumerate() {
+        if *byte != b'\r' || output.get(idx + 1) != Some(&b'\n') {
+            result.push(*byte);
+        }
+    }
+    result
+}
+
+/// Run `builder`'s command once, on a terminal. Everything it prints ends up on stdout.
+/// Input is typed at the terminal and followed by ^D, so it should end with a newline.
+pub(crate) async fn attempt(builder: &CommandBuilder, echo: bool) -> Result<CommandOutput> {
+    let (mut proc, pty) = spawn_on_pty(builder).await?;
+    let reader = pty.master()?;
+    let mut writer = pty.master()?;
+    drop(pty);
+    let input = open_input(builder.get_input()).await?;
+    let input_task = input.map(|mut input| {
+        tokio::spawn(async move {
+            tokio::io::copy(&mut input, &mut writer).await?;
+            writer.write_all(&[EOF_CHAR]).await?;
+            writer.flush().await
+        })
+    });
+    let hub = LineHub::new(builder.get_watchers(), 1, builder.records_transcript());
+    let mut options = builder.echo_options(OutputStream::Stdout, true, &builder.secrets(), &hub);
+    options.echo = echo;
+    options.stop = Some(proc.stop_signal());
+    let output_task = tokio::spawn(crate::commands::echo_lines(reader, options));
+    proc.lines = Some(hub);
+    let (status, timed_out) = builder.wait_for(&mut proc).await?;
+    if let Some(task) = input_task {
+        // The child may have exited without reading it all.
+        task.abort();
+    }
+    // Any grandchildren holding the terminal open shouldn't keep us waiting.
+    let mut stdout = proc.drain(output_task).await?;
+    stdout.data = normalise_newlines(&stdout.data);
+    let mut result = builder.make_output(
+        status,
+        timed_out,
+        stdout,
+        Default::default(),
+        proc.usage().cloned(),
+    );
+    result.transcript = proc.take_transcript();
+    proc.record_completion(&result);
+    Ok(result)
+}
+
+#[derive(Debug, Default)]
+struct SessionBuffer {
+    data: Vec<u8>,
+    ended: bool,
+}
+
+/// A command running on a terminal, which we talk to expect-style: wait for a prompt,
+/// answer it, repeat.
+pub struct PtySession {
+    builder: CommandBuilder,
+    proc: ChildProcess,
+    writer: tokio::fs::File,
+    buffer: Arc<Mutex<SessionBuffer>>,
+    /// Poked whenever more output arrives.
+    more: Arc<Notify>,
+    reader: JoinHandle<()>,
+    /// How much of the output expect() has consumed.
+    cursor: usize,
+    /// Things we typed that should not appear in the transcript.
+    secrets: Vec<String>,
+}
+
+impl PtySession {
+    pub(crate) async fn spawn(builder: &CommandBuilder) -> Result<Self> {
+        let (proc, pty) = spawn_on_pty(builder).await?;
+        let mut reader = pty.master()?;
+        let writer = pty.master()?;
+        drop(pty);
+        let buffer = Arc::new(Mutex::new(SessionBuffer::default()));
+        let more = Arc::new(Notify::new());
+        let reader = {
+            let buffer = buffer.clone();
+            let more = more.clone();
+            tokio::spawn(async move {
+                let mut chunk = [0u8; 4096];
+                loop {
+                    // The master reports EIO once the child has gone.
+                    let count = reader.read(&mut chunk).await.unwrap_or(0);
+                    if let Ok(mut buffer) = buffer.lock() {
+                        buffer.data.extend_from_slice(&chunk[..count]);
+                        buffer.ended = count == 0;
+                    }
+                    more.notify_one();
+                    if count == 0 {
+                        break;
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            builder: builder.clone(),
+            proc,
+            writer,
+            buffer,
+            more,
+            reader,
+            cursor: 0,
+            secrets: builder.secrets(),
+        })
+    }
+
+    /// Type `text`.
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        self.writer.write_all(text.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn send_line(&mut self, line: &str) -> Result<()> {
+        self.send(&format!("{line}\n")).await
+    }
+
+    /// Type a password or the like, which is masked in the transcript.
+    pub async fn send_secret_line(&mut self, line: &str) -> Result<()> {
+        if !line.is_empty() {
+            self.secrets.push(line.to_string());
+        }
+        self.send_line(line).await
+    }
+
+    /// Type ^D.
+    pub async fn send_eof(&mut self) -> Result<()> {
+        self.writer.write_all(&[EOF_CHAR]).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Wait for output matching `pattern` and return everything up to and including the
+    /// match, which is then consumed - so the next expect() starts after it.
+    pub async fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<String> {
+        let re = Regex::new(pattern)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            {
+                let buffer = self
+                    .buffer
+                    .lock()
+                    .map_err(|_| anyhow!("Terminal buffer lock is poisoned"))?;
+                let unread = &buffer.data[self.cursor..];
+                if let Some(found) = re.find(unread) {
+                    let text = String::from_utf8_lossy(&unread[..found.end()]).to_string();
+                    self.cursor += found.end();
+                    return Ok(text);
+                }
+                if buffer.ended {
+                    return Err(anyhow!(
+                        "Output ended without anything matching {pattern}\n{0}",
+                        self.redact(unread)
+                    ));
+                }
+            }
+            if time::timeout_at(deadline, self.more.notified())
+                .await
+                .is_err()
+            {
+                return Err(anyhow!(
+                    "Timed out after {timeout:?} waiting for {pattern}\n{0}",
+                    self.transcript_tail()
+                ));
+            }
+        }
+    }
+
+    /// Everything the command has printed so far (including what the terminal echoed of
+    /// what we typed), with secrets masked.
+    pub fn transcript(&self) -> String {
+        match self.buffer.lock() {
+            Ok(buffer) => self.redact(&buffer.data),
+            Err(_) => String::new(),
+        }
+    }
+
+    fn transcript_tail(&self) -> String {
+        match self.buffer.lock() {
+            Ok(buffer) => self.redact(&buffer.data[self.cursor..]),
+            Err(_) => String::new(),
+        }
+    }
+
+    fn redact(&self, data: &[u8]) -> String {
+        redact::redact_with(
+            &utils::string_or_empty_from_u8(&normalise_newlines(data)),
+            &self.secrets,
+        )
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.proc.child.id()
+    }
+
+    /// Wait for the command to exit (subject to the builder's timeout). The output's
+    /// stdout is the redacted transcript.
+    pub async fn wait(mut self) -> Result<CommandOutput> {
+        let (status, timed_out) = self.builder.wait_for(&mut self.proc).await?;
+        // Any grandchildren holding the terminal open shouldn't keep us waiting.
+        if time::timeout(DRAIN_GRACE, &mut self.reader).await.is_err() {
+            self.reader.abort();
+        }
+        let stdout = self.transcript().into_bytes();
+        let result = self.builder.make_output(
+            status,
+            timed_out,
+            stdout.into(),
+            Default::default(),
+            self.proc.usage().cloned(),
+        );
+        self.proc.record_completion(&result);
+        self.builder.check(result)
+    }
+}
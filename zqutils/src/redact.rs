@@ -0,0 +1,49 @@
+use std::sync::RwLock;
+
+/// What secrets are replaced with.
+pub const MASK: &str = "***";
+
+/// Secrets to mask wherever we print them.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Mask `secret` in everything we print from now on - command output, descriptions,
+/// audit logs and error messages.
+pub fn register_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    if let Ok(mut secrets) = SECRETS.write() {
+        if !secrets.iter().any(|x| x == secret) {
+            secrets.push(secret.to_string());
+            // Longest first, so that a secret containing another is masked whole.
+            secrets.sort_by_key(|x| std::cmp::Reverse(x.len()));
+        }
+    }
+}
+
+pub fn forget_secrets() {
+    if let Ok(mut secrets) = SECRETS.write() {
+        secrets.clear();
+    }
+}
+
+/// Mask registered secrets in `text`.
+pub fn redact(text: &str) -> String {
+    redact_with(text, &[])
+}
+
+/// Mask registered secrets, and `extra`, in `text`.
+pub fn redact_with(text: &str, extra: &[String]) -> String {
+    let mut result = text.to_string();
+    let mut extra: Vec<&String> = extra.iter().filter(|x| !x.is_empty()).collect();
+    extra.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    for secret in extra {
+        result = result.replace(secret.as_str(), MASK);
+    }
+    if let Ok(secrets) = SECRETS.read() {
+        for secret in secrets.iter() {
+            result = result.replace(secret.as_str(), MASK);
+        }
+    }
+    result
+}
@@ -0,0 +1,161 @@
+use crate::process;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+/// Every child we have started and not yet seen exit, by pid.
+static CHILDREN: Mutex<BTreeMap<u32, TrackedChild>> = Mutex::new(BTreeMap::new());
+/// Should we catch SIGINT and SIGTERM and pass them on?
+static FORWARD_SIGNALS: AtomicBool = AtomicBool::new(false);
+static HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+/// How long children get to exit before we SIGKILL them.
+static GRACE_MS: AtomicU64 = AtomicU64::new(5000);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedChild {
+    pub pid: u32,
+    /// If so, we signal its whole process group.
+    pub session_leader: bool,
+    /// The command, with secrets masked.
+    pub description: String,
+}
+
+/// Whether to catch SIGINT and SIGTERM and pass them on to our children, then exit. Off
+/// by default, so that a library doesn't take over the process's signals unasked;
+/// script::Context and zutil turn it on. If you handle signals yourself, leave it off
+/// and call shutdown() when you get one. Once we have started catching them, turning
+/// this off again leaves them caught: they no longer kill the process, so only do that
+/// while you are handling them. Returns the previous setting.
+pub fn forward_signals(enabled: bool) -> bool {
+    let previous = FORWARD_SIGNALS.swap(enabled, Ordering::SeqCst);
+    if enabled {
+        install_handler();
+    }
+    previous
+}
+
+/// How long children get to exit after we pass a signal on, before we SIGKILL them.
+pub fn set_grace(grace: Duration) {
+    GRACE_MS.store(
+        u64::try_from(grace.as_millis()).unwrap_or(u64::MAX),
+        Ordering::SeqCst,
+    );
+}
+
+/// The children we are tracking.
+pub fn tracked() -> Vec<TrackedChild> {
+    CHILDREN
+        .lock()
+        .map(|x| x.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Remember a child we just started, so that we can clean it up if we are interrupted.
+pub(crate) fn track(pid: u32, session_leader: bool, description: &str) {
+    if let Ok(mut children) = CHILDREN.lock() {
+        // Forget anything that has gone without our noticing, so we don't signal a
+        // recycled pid.
+        children.retain(|pid, _| is_running_child(*pid));
+        children.insert(
+            pid,
+            TrackedChild {
+                pid,
+                session_leader,
+                description: description.to_string(),
+            },
+        );
+    }
+    install_handler();
+}
+
+/// Forget a child which has exited.
+pub(crate) fn untrack(pid: u32) {
+    if let Ok(mut children) = CHILDREN.lock() {
+        children.remove(&pid);
+    }
+}
+
+fn install_handler() {
+    if !FORWARD_SIGNALS.load(Ordering::SeqCst) || HANDLER_INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if tokio::runtime::Handle::try_current().is_err() {
+        HANDLER_INSTALLED.store(false, Ordering::SeqCst);
+        return;
+    }
+    let (mut interrupt, mut terminate) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("⚠️ Cannot catch signals - {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let sig = loop {
+            let sig = tokio::select! {
+                _ = interrupt.recv() => libc::SIGINT,
+                _ = terminate.recv() => libc::SIGTERM,
+            };
+            // Otherwise, whoever turned us off is handling it.
+            if FORWARD_SIGNALS.load(Ordering::SeqCst) {
+                break sig;
+            }
+        };
+        println!(
+            "\r\n🛑 Caught signal {sig} - stopping {0} children",
+            tracked().len()
+        );
+        shutdown(sig, Duration::from_millis(GRACE_MS.load(Ordering::SeqCst))).await;
+        std::process::exit(128 + sig);
+    });
+}
+
+/// Send `sig` to every tracked child, wait up to `grace` for them to exit, and SIGKILL
+/// any that haven't.
+pub async fn shutdown(sig: i32, grace: Duration) {
+    let children = tracked();
+    for child in &children {
+        signal_child(child, sig);
+    }
+    let deadline = Instant::now() + grace;
+    let mut running: Vec<&TrackedChild> = children.iter().collect();
+    while !running.is_empty() && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(50)).await;
+        running.retain(|x| is_running_child(x.pid));
+    }
+    for child in running {
+        println!("⏰ {0} is still running - killing it", child.description);
+        signal_child(child, libc::SIGKILL);
+    }
+}
+
+/// Signal `child`, if it is still ours to signal.
+fn signal_child(child: &TrackedChild, sig: i32) {
+    if !is_running_child(child.pid) {
+        return;
+    }
+    if let Ok(pid) = i32::try_from(child.pid) {
+        if child.session_leader {
+            process::kill(-pid, sig);
+        } else {
+            process::kill(pid, sig);
+        }
+    }
+}
+
+/// Is `pid` a child of ours which is still running? Not if it has exited, whether or not
+/// it has been reaped: once it has, its pid may belong to some other process.
+fn is_running_child(pid: u32) -> bool {
+    // Safe: siginfo_t is plain old data.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    // Fails if it isn't our child; sets si_pid if it has exited, without reaping it.
+    let result = unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) };
+    result == 0 && unsafe { info.si_pid() } == 0
+}
@@ -0,0 +1,159 @@
+use crate::commands::{CommandBuilder, CommandOutput};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Something which can run a CommandBuilder. CommandBuilder takes care of retries and of
+/// failing on failure; the runner just runs the command once and says what happened.
+pub trait CommandRunner: Debug + Send + Sync {
+    /// Run `cmd` once, logging its output if `logged` is set, or capturing it otherwise.
+    fn run_once<'a>(
+        &'a self,
+        cmd: &'a CommandBuilder,
+        logged: bool,
+    ) -> BoxFuture<'a, Result<CommandOutput>>;
+}
+
+/// Really runs commands.
+#[derive(Debug, Default)]
+pub struct SystemRunner {}
+
+impl CommandRunner for SystemRunner {
+    fn run_once<'a>(
+        &'a self,
+        cmd: &'a CommandBuilder,
+        logged: bool,
+    ) -> BoxFuture<'a, Result<CommandOutput>> {
+        Box::pin(async move {
+            if logged {
+                cmd.attempt_logged().await
+            } else {
+                cmd.attempt_for_output().await
+            }
+        })
+    }
+}
+
+/// The runner to use when no-one has asked for anything else.
+pub fn system() -> Arc<dyn CommandRunner> {
+    Arc::new(SystemRunner::default())
+}
+
+/// A command a FakeRunner was asked to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeCall {
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<String>,
+}
+
+#[derive(Debug)]
+struct FakeResponse {
+    cmd: String,
+    /// None matches any arguments.
+    args: Option<Vec<String>>,
+    output: CommandOutput,
+}
+
+impl FakeResponse {
+    fn matches(&self, cmd: &str, args: &[String]) -> bool {
+        self.cmd == cmd && self.args.as_ref().is_none_or(|x| x == args)
+    }
+}
+
+/// A runner for tests: answers commands with scripted CommandOutputs and remembers what
+/// it was asked to run.
+///
+///  * Responses are matched on command and arguments, in the order they were added.
+///  * Each response is used once, except that the last response matching a command is
+///    used for every call after that.
+///  * A command nothing matches is an error.
+#[derive(Debug, Default)]
+pub struct FakeRunner {
+    responses: Mutex<Vec<FakeResponse>>,
+    calls: Mutex<Vec<FakeCall>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer `cmd` with exactly these `args` with `output`.
+    pub fn respond(&self, cmd: &str, args: &[&str], output: CommandOutput) -> &Self {
+        self.push(FakeResponse {
+            cmd: cmd.to_string(),
+            args: Some(args.iter().map(|x| x.to_string()).collect()),
+            output,
+        })
+    }
+
+    /// Answer `cmd`, whatever its arguments, with `output`.
+    pub fn respond_any(&self, cmd: &str, output: CommandOutput) -> &Self {
+        self.push(FakeResponse {
+            cmd: cmd.to_string(),
+            args: None,
+            output,
+        })
+    }
+
+    fn push(&self, response: FakeResponse) -> &Self {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.push(response);
+        }
+        self
+    }
+
+    /// Everything we have been asked to run so far.
+    pub fn calls(&self) -> Vec<FakeCall> {
+        self.calls.lock().map(|x| x.clone()).unwrap_or_default()
+    }
+
+    fn answer(&self, cmd: &CommandBuilder) -> Result<CommandOutput> {
+        let name = cmd.get_cmd().ok_or(anyhow!("No command specified"))?;
+        let args = cmd.get_args();
+        self.calls
+            .lock()
+            .map_err(|_| anyhow!("FakeRunner call log is poisoned"))?
+            .push(FakeCall {
+                cmd: name.to_string(),
+                args: args.to_vec(),
+                env: cmd.get_env().cloned().unwrap_or_default(),
+                cwd: cmd.get_cwd().map(|x| x.to_string()),
+            });
+        let mut responses = self
+            .responses
+            .lock()
+            .map_err(|_| anyhow!("FakeRunner responses are poisoned"))?;
+        let mut matching = responses
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.matches(name, args))
+            .map(|(idx, _)| idx);
+        let first = matching.next().ok_or(anyhow!(
+            "FakeRunner has no response for {0}",
+            cmd.describe_command()?
+        ))?;
+        if matching.next().is_some() {
+            Ok(responses.remove(first).output)
+        } else {
+            Ok(responses[first].output.clone())
+        }
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run_once<'a>(
+        &'a self,
+        cmd: &'a CommandBuilder,
+        _logged: bool,
+    ) -> BoxFuture<'a, Result<CommandOutput>> {
+        Box::pin(async move { self.answer(cmd) })
+    }
+}
@@ -1,14 +1,49 @@
-use crate::{commands, utils};
+use crate::manifest::{Manifest, PlannedStep};
+use crate::mode::ExecutionMode;
+use crate::packages::{PackageManager, PackageNames};
+use crate::runner::{self, CommandRunner};
+use crate::target::ExecutionTarget;
+use crate::{commands, registry, utils};
 use anyhow::{anyhow, Result};
 use home;
 use reqwest;
 use std::collections::HashMap;
-use std::env;
+use std::fmt;
 use std::os::unix::fs::PermissionsExt as _;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
 
+/// How a provisioning step went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepStatus {
+    /// It was already done, or only looks at things.
+    Unchanged,
+    Changed,
+    /// We didn't run it, and why.
+    Skipped(String),
+    /// It failed. Unless the step was mandatory, we carried on.
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepReport {
+    pub description: String,
+    pub status: StepStatus,
+}
+
+impl fmt::Display for StepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.status {
+            StepStatus::Unchanged => write!(f, "✅ {0}: unchanged", self.description),
+            StepStatus::Changed => write!(f, "🔧 {0}: changed", self.description),
+            StepStatus::Skipped(why) => write!(f, "⏭️ {0}: skipped ({why})", self.description),
+            StepStatus::Failed(why) => write!(f, "❌ {0}: failed - {why}", self.description),
+        }
+    }
+}
+
 pub struct Context {
     /// Dry run or really execute?
     pub really_execute: bool,
@@ -16,24 +51,52 @@ pub struct Context {
     pub append_paths: Vec<String>,
     pub os_params: HashMap<String, String>,
     pub arch: String,
+    /// Runs every command we execute.
+    pub runner: Arc<dyn CommandRunner>,
+    /// How every step so far went.
+    reports: Mutex<Vec<StepReport>>,
+    /// None if we don't recognise the distribution.
+    pub package_manager: Option<PackageManager>,
+    pub package_names: PackageNames,
 }
 
 impl Context {
+    /// Creating a context turns on registry::forward_signals(), so that interrupting a
+    /// script stops the commands it started.
     pub async fn new(really_execute: bool) -> Result<Self> {
+        Context::new_with_runner(really_execute, runner::system()).await
+    }
+
+    pub async fn new_with_runner(
+        really_execute: bool,
+        runner: Arc<dyn CommandRunner>,
+    ) -> Result<Self> {
+        registry::forward_signals(true);
         let os_params = Context::get_os_params().await?;
-        let arch = Context::get_arch().await?;
+        let arch = Context::get_arch_with(&runner).await?;
         Ok(Self {
             really_execute,
             append_paths: Vec::new(),
             vars: HashMap::new(),
             arch,
+            package_manager: PackageManager::detect(&os_params).ok(),
             os_params,
+            runner,
+            reports: Mutex::new(Vec::new()),
+            package_names: PackageNames::default(),
         })
     }
 
     pub async fn get_arch() -> Result<String> {
+        Context::get_arch_with(&runner::system()).await
+    }
+
+    pub async fn get_arch_with(runner: &Arc<dyn CommandRunner>) -> Result<String> {
         let mut cmd = commands::CommandBuilder::new();
-        cmd.cmd("arch", &[]).silent();
+        cmd.cmd("arch", &[])
+            .silent()
+            .read_only()
+            .runner(runner.clone());
         let result = cmd.run_for_output().await?.sanitise_stdout()?;
         Ok(result)
     }
@@ -54,6 +117,119 @@ impl Context {
         Ok(result)
     }
 
+    /// Are we only saying what we would do?
+    pub fn is_dry_run(&self) -> bool {
+        !self.really_execute || ExecutionMode::current().is_dry_run()
+    }
+
+    /// Load and validate the manifest at `path`.
+    pub fn load_manifest(path: &Path) -> Result<Manifest> {
+        Manifest::load(path)
+    }
+
+    /// Say what apply_manifest() would do, without doing it.
+    pub fn plan_manifest(&self, manifest: &Manifest) -> Vec<PlannedStep> {
+        let plan = manifest.plan(self);
+        for step in &plan {
+            println!("📋 {step}");
+        }
+        plan
+    }
+
+    /// Run `manifest`'s steps - or, in a dry run, say what they would do.
+    pub async fn apply_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        manifest.apply(self).await
+    }
+
+    /// Note how a step went.
+    pub fn record_step(&self, description: &str, status: StepStatus) {
+        let report = StepReport {
+            description: description.to_string(),
+            status,
+        };
+        println!("{report}");
+        if let Ok(mut reports) = self.reports.lock() {
+            reports.push(report);
+        }
+    }
+
+    pub fn step_reports(&self) -> Vec<StepReport> {
+        self.reports.lock().map(|x| x.clone()).unwrap_or_default()
+    }
+
+    /// How many steps were unchanged, changed, skipped and failed.
+    pub fn summary(&self) -> String {
+        let reports = self.step_reports();
+        let count =
+            |want: fn(&StepStatus) -> bool| reports.iter().filter(|x| want(&x.status)).count();
+        format!(
+            "{0} unchanged, {1} changed, {2} skipped, {3} failed",
+            count(|x| *x == StepStatus::Unchanged),
+            count(|x| *x == StepStatus::Changed),
+            count(|x| matches!(x, StepStatus::Skipped(_))),
+            count(|x| matches!(x, StepStatus::Failed(_)))
+        )
+    }
+
+    /// Run `cmd` as a step: skip it if its probe says it's already done, and otherwise
+    /// run it, reporting how it went. Failure is an error only if the step is mandatory.
+    pub async fn run_step(&self, cmd: &mut Command) -> Result<StepStatus> {
+        let description = cmd.describe()?;
+        self.modify_context(&mut cmd.cmd).await?;
+        if !cmd.imperative {
+            cmd.cmd.read_only();
+        }
+        let status = if self.probe_satisfied(cmd).await {
+            StepStatus::Unchanged
+        } else {
+            match cmd.execute(self).await {
+                Ok(_) if !cmd.imperative => StepStatus::Unchanged,
+                Ok(_) if self.is_dry_run() => StepStatus::Skipped("dry run".to_string()),
+                Ok(_) => StepStatus::Changed,
+                Err(e) => {
+                    self.record_step(&description, StepStatus::Failed(e.to_string()));
+                    if cmd.mandatory {
+                        return Err(e);
+                    }
+                    println!("⚠️ {description} is optional - carrying on");
+                    return Ok(StepStatus::Failed(e.to_string()));
+                }
+            }
+        };
+        self.record_step(&description, status.clone());
+        Ok(status)
+    }
+
+    /// Does `cmd`'s probe say it has already been done? A probe we can't run says no.
+    async fn probe_satisfied(&self, cmd: &Command) -> bool {
+        let Some(probe) = &cmd.probe else {
+            return false;
+        };
+        let mut probe = probe.clone();
+        probe
+            .runner(self.runner.clone())
+            .read_only()
+            .silent()
+            .ignore_failures();
+        if let Err(e) = self.modify_context(&mut probe).await {
+            println!(
+                "⚠️ Cannot probe {0} - {e}",
+                cmd.describe().unwrap_or_default()
+            );
+            return false;
+        }
+        match probe.run_for_output().await {
+            Ok(output) => output.success,
+            Err(e) => {
+                println!(
+                    "⚠️ Cannot probe {0} - {e}",
+                    cmd.describe().unwrap_or_default()
+                );
+                false
+            }
+        }
+    }
+
     pub fn add_to_path(&mut self, path_str: &str) {
         self.append_paths.push(path_str.to_string());
     }
@@ -62,89 +238,177 @@ impl Context {
         self.vars.insert(name.to_string(), val.to_string());
     }
 
+    /// Give `builder` our PATH entries and variables. Doing so again changes nothing, so
+    /// a step can be run more than once.
     pub async fn modify_context(&self, builder: &mut commands::CommandBuilder) -> Result<()> {
-        let current_path = env::var("PATH")?;
-        let mut my_path: Vec<String> = vec![current_path.to_string()];
         for path in &self.append_paths {
-            my_path.push(path.to_string());
+            let present = builder
+                .get_env()
+                .and_then(|x| x.get("PATH"))
+                .is_some_and(|x| x.split(':').any(|x| x == path));
+            if !present {
+                builder.path_append(path);
+            }
         }
-        builder.env_var("PATH", &my_path.join(":"));
         for (k, v) in &self.vars {
             builder.env_var(k.as_str(), v.as_str());
         }
         Ok(())
     }
 
-    pub async fn apt_update(&self) -> Result<()> {
-        let mut cmd = Command::as_root(&["apt", "update"])?;
-        self.modify_context(&mut cmd.cmd).await?;
-        cmd.execute(self).await?;
+    fn packages(&self) -> Result<PackageManager> {
+        self.package_manager.ok_or(anyhow!(
+            "Don't know the package manager for {0}",
+            self.os_params
+                .get("PRETTY_NAME")
+                .map(|x| x.trim_matches('"'))
+                .unwrap_or("this distribution")
+        ))
+    }
+
+    /// Run a package manager command line as root, unattended.
+    async fn package_step(
+        &self,
+        manager: PackageManager,
+        words: &[String],
+        probe: Option<commands::CommandBuilder>,
+    ) -> Result<()> {
+        let words: Vec<&str> = words.iter().map(|x| x.as_str()).collect();
+        let mut cmd = Command::as_root(&words)?;
+        for (name, val) in manager.env() {
+            cmd.builder().env_var(name, val);
+        }
+        if let Some(probe) = probe {
+            cmd.probe(&probe);
+        }
+        self.run_step(&mut cmd).await?;
         Ok(())
     }
 
+    /// Refresh the package lists, with whatever package manager we have.
+    pub async fn update_packages(&self) -> Result<()> {
+        let manager = self.packages()?;
+        self.package_step(manager, &manager.update_command(), None)
+            .await
+    }
+
+    pub async fn upgrade_packages(&self) -> Result<()> {
+        let manager = self.packages()?;
+        self.package_step(manager, &manager.upgrade_command(), None)
+            .await
+    }
+
+    /// Install `pkgs`, given their Debian names, unless they are all installed already.
+    pub async fn install_packages(&self, pkgs: &[&str]) -> Result<()> {
+        let manager = self.packages()?;
+        let pkgs = self.package_names.resolve(manager, pkgs);
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+        let probe = manager.installed_probe(&pkgs);
+        self.package_step(manager, &manager.install_command(&pkgs), Some(probe))
+            .await
+    }
+
+    /// Remove `pkgs`, given their Debian names.
+    pub async fn remove_packages(&self, pkgs: &[&str]) -> Result<()> {
+        let manager = self.packages()?;
+        let pkgs = self.package_names.resolve(manager, pkgs);
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+        self.package_step(manager, &manager.remove_command(&pkgs), None)
+            .await
+    }
+
+    /// The installed version of `pkg`, given its Debian name, or None if it isn't
+    /// installed. If it maps to several packages, the version of the first.
+    pub async fn installed_version(&self, pkg: &str) -> Result<Option<String>> {
+        let manager = self.packages()?;
+        let name = self
+            .package_names
+            .resolve(manager, &[pkg])
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("{pkg} is not a package on {manager}"))?;
+        let output = manager
+            .version_query(&name)
+            .runner(self.runner.clone())
+            .read_only()
+            .silent()
+            .ignore_failures()
+            .run_for_output()
+            .await?;
+        Ok(manager.parse_version(&name, &output))
+    }
+
+    /// As update_packages() - whatever the package manager.
+    pub async fn apt_update(&self) -> Result<()> {
+        self.update_packages().await
+    }
+
+    /// As upgrade_packages() - whatever the package manager.
     pub async fn apt_upgrade(&self) -> Result<()> {
-        let mut cmd = Command::as_root(&["apt", "dist-upgrade"])?;
-        self.modify_context(&mut cmd.cmd).await?;
-        cmd.execute(self).await?;
-        Ok(())
+        self.upgrade_packages().await
     }
 
+    /// As remove_packages() - whatever the package manager.
     pub async fn apt_remove(&self, pkgs: &Vec<&str>) -> Result<()> {
-        let mut fst = vec!["apt", "remove", "-q", "-y"];
-        fst.extend(pkgs);
-        let mut the_cmd = Command::as_root(&fst)?;
-        the_cmd
-            .builder()
-            .env_var("DEBIAN_FRONTEND", "noninteractive");
-        self.modify_context(&mut the_cmd.cmd).await?;
-        the_cmd.execute(self).await?;
-        Ok(())
+        self.remove_packages(pkgs).await
     }
 
     pub async fn install_keyring(&self, url: &str, name: &str) -> Result<()> {
+        let description = format!("install keyring {name}");
         let dir_path = PathBuf::from("/etc/apt/keyrings");
-        if !dir_path.is_dir() {
-            fs::create_dir_all(&dir_path).await?;
-        }
         let mut name_path = dir_path.clone();
         name_path.push(name);
 
-        if !name_path.exists() {
+        if name_path.exists() {
+            self.record_step(&description, StepStatus::Unchanged);
+            return Ok(());
+        }
+        if self.is_dry_run() {
+            println!("🔍 Dry run: install keyring {name} from {url}");
+            self.record_step(&description, StepStatus::Skipped("dry run".to_string()));
+            return Ok(());
+        }
+        let result = async {
+            if !dir_path.is_dir() {
+                fs::create_dir_all(&dir_path).await?;
+            }
             println!("Downloading keyring {name} from {url} .. ");
-            let body = reqwest::get(url).await?.text().await?;
+            let body = reqwest::get(url).await?.bytes().await?;
             let name_path = utils::string_from_path(&name_path)?;
             let mut cmd = commands::CommandBuilder::new();
-            cmd.cmd("gpg", &["--dearmor", "-o", &name_path]);
-            cmd.run_logged_with_input(&body).await?;
+            cmd.cmd("gpg", &["--dearmor", "-o", &name_path])
+                .input_bytes(&body)
+                .runner(self.runner.clone());
+            cmd.run_logged().await?;
             fs::set_permissions(name_path, std::fs::Permissions::from_mode(0o644)).await?;
+            Ok(())
         }
-        Ok(())
+        .await;
+        self.record_result(&description, result)
     }
 
+    /// As install_packages() - whatever the package manager.
     pub async fn apt_install(&self, pkgs: &Vec<&str>) -> Result<()> {
-        let mut fst = vec!["apt", "install", "-q", "-y"];
-        fst.extend(pkgs);
-        let mut the_cmd = Command::as_root(&fst)?;
-        the_cmd
-            .builder()
-            .env_var("DEBIAN_FRONTEND", "noninteractive");
-        self.modify_context(&mut the_cmd.cmd).await?;
-        the_cmd.execute(self).await?;
-        Ok(())
+        self.install_packages(pkgs).await
     }
 
     pub async fn as_root(&self, cmd: &[&str]) -> Result<()> {
         let mut cmd = Command::as_root(cmd)?;
-        self.modify_context(&mut cmd.cmd).await?;
-        cmd.execute(self).await?;
+        self.run_step(&mut cmd).await?;
         Ok(())
     }
 
     pub async fn append_bashrc(&self, id: &str, what: &Vec<&str>) -> Result<()> {
+        let description = format!("bashrc block {id}");
         let mut bashrc = home::home_dir().ok_or(anyhow!("Can't get your home directory"))?;
         bashrc.push(".bashrc");
         // Is this already in bashrc
         let mut contents = fs::read_to_string(&bashrc).await?;
+        let original = contents.clone();
         // This is crude, and doesn't account for conditionals, but.
         let id_begin_str = format!("# zws_auto begin {id}");
         let id_end_str = format!("# zws_auto end {id}");
@@ -173,9 +437,31 @@ impl Context {
             contents.push_str(&id_end_str);
             contents.push('\n');
         }
-        let mut f = File::create(&bashrc).await?;
-        f.write_all(contents.as_bytes()).await?;
-        Ok(())
+        if contents == original {
+            self.record_step(&description, StepStatus::Unchanged);
+            return Ok(());
+        }
+        if self.is_dry_run() {
+            println!("🔍 Dry run: set bashrc block {id} to {0} lines", what.len());
+            self.record_step(&description, StepStatus::Skipped("dry run".to_string()));
+            return Ok(());
+        }
+        let result = async {
+            let mut f = File::create(&bashrc).await?;
+            f.write_all(contents.as_bytes()).await?;
+            Ok(())
+        }
+        .await;
+        self.record_result(&description, result)
+    }
+
+    /// Report a step we did ourselves as changed, or failed.
+    fn record_result(&self, description: &str, result: Result<()>) -> Result<()> {
+        match &result {
+            Ok(()) => self.record_step(description, StepStatus::Changed),
+            Err(e) => self.record_step(description, StepStatus::Failed(e.to_string())),
+        }
+        result
     }
 
     pub async fn shell(&self, cmd: &str) -> Result<()> {
@@ -183,8 +469,7 @@ impl Context {
         // the -i here makes the shell interactive - the default .bashrc on Ubuntu refuses to do
         // anything otherwise. We need to source .bashrc or things like nvm won't be added to the path.
         let mut cmd = Command::build("bash", &["-c", cmd])?;
-        self.modify_context(&mut cmd.cmd).await?;
-        cmd.execute(self).await?;
+        self.run_step(&mut cmd).await?;
         Ok(())
     }
 
@@ -203,33 +488,44 @@ impl Context {
                 tgt,
             ],
         )?;
-        cmd.execute(self).await?;
+        self.run_step(&mut cmd).await?;
         Ok(())
     }
 }
 
 pub struct Command {
+    /// Is failure an error, or just a warning?
     pub mandatory: bool,
+    /// Does the command change things, or just look at them? Steps that only look run
+    /// even in a dry run.
     pub imperative: bool,
     pub cmd: commands::CommandBuilder,
+    /// Succeeds if the command has already been done.
+    pub probe: Option<commands::CommandBuilder>,
 }
 
 impl Command {
     pub fn new() -> Result<Self> {
         Ok(Self {
             mandatory: false,
-            imperative: false,
+            // Only steps marked interrogative are safe to run in a dry run.
+            imperative: true,
             cmd: commands::CommandBuilder::new(),
+            probe: None,
         })
     }
 
     pub fn as_root(args: &[&str]) -> Result<Self> {
+        let (name, rest) = args
+            .split_first()
+            .ok_or(anyhow!("No command to run as root"))?;
         let mut cmd = commands::CommandBuilder::new();
-        cmd.cmd("sudo", args);
+        cmd.cmd(name, rest).target(&ExecutionTarget::sudo());
         Ok(Self {
             mandatory: true,
             imperative: true,
             cmd,
+            probe: None,
         })
     }
 
@@ -240,16 +536,27 @@ impl Command {
             mandatory: true,
             imperative: true,
             cmd,
+            probe: None,
         })
     }
 
     pub async fn execute(&mut self, ctx: &Context) -> Result<commands::CommandOutput> {
-        if ctx.really_execute {
-            Ok(self.cmd.run_logged().await?)
-        } else {
-            println!("{0}", self.cmd.describe_command()?);
-            Ok(commands::CommandOutput::fake(true))
+        self.cmd.runner(ctx.runner.clone());
+        if !ctx.really_execute {
+            self.cmd.dry_run();
         }
+        self.cmd.run_logged().await
+    }
+
+    /// Check `probe` before running the command as a step, and skip the step if it
+    /// succeeds.
+    pub fn probe(&mut self, probe: &commands::CommandBuilder) -> &mut Self {
+        self.probe = Some(probe.clone());
+        self
+    }
+
+    pub fn describe(&self) -> Result<String> {
+        self.cmd.describe_command_line()
     }
 
     pub fn builder(&mut self) -> &mut commands::CommandBuilder {
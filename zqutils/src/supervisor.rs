@@ -0,0 +1,410 @@
+use crate::commands::{ChildProcess, CommandBuilder, CommandOutput, KillPolicy, RetryPolicy};
+use crate::{process, redact, registry};
+use anyhow::{anyhow, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// What to do when a supervised command exits. The retry policy says how long to wait
+/// between restarts and how many times (`max_attempts`) to start the command in all.
+#[derive(Debug, Clone)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart if the command fails in a way the policy says is worth retrying.
+    OnFailure(RetryPolicy),
+    /// Restart however the command exits.
+    Always(RetryPolicy),
+}
+
+/// Where a supervised command's output goes. Once the file reaches `max_bytes`, it is
+/// renamed to `<path>.1` (and `<path>.1` to `<path>.2`, and so on), keeping `keep` old
+/// files.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+impl LogFile {
+    pub fn new(path: &std::path::Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+struct RotatingLog {
+    config: LogFile,
+    file: File,
+    written: u64,
+}
+
+impl RotatingLog {
+    fn open(config: &LogFile) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            config: config.clone(),
+            file,
+            written,
+        })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut name = self.config.path.clone().into_os_string();
+        name.push(format!(".{idx}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            for idx in (1..self.config.keep).rev() {
+                let from = self.rotated_path(idx);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(idx + 1))?;
+                }
+            }
+            fs::rename(&self.config.path, self.rotated_path(1))?;
+        }
+        self.file = File::create(&self.config.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.written >= self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.written += u64::try_from(line.len() + 1)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceStatus {
+    Starting,
+    Running {
+        pid: u32,
+    },
+    /// Waiting to be restarted.
+    Restarting,
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// Could not be started.
+    Failed(String),
+    Stopped,
+}
+
+struct Service {
+    name: String,
+    status: Mutex<ServiceStatus>,
+    restarts: Mutex<u32>,
+    pid: Mutex<Option<u32>>,
+    stopping: AtomicBool,
+    stop: Notify,
+    /// Writes the log, and finishes once the command is done with it.
+    writer: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl Service {
+    fn set_status(&self, status: ServiceStatus) {
+        if let Ok(mut pid) = self.pid.lock() {
+            *pid = match status {
+                ServiceStatus::Running { pid } => Some(pid),
+                _ => None,
+            };
+        }
+        if let Ok(mut current) = self.status.lock() {
+            *current = status;
+        }
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// Owns a set of background commands, logging their output to files, restarting them
+/// as their policies say and stopping them - in the reverse of the order they were added
+/// in - when asked, on Ctrl-C or when dropped.
+pub struct Supervisor {
+    services: Vec<(Arc<Service>, JoinHandle<()>)>,
+    kill_policy: KillPolicy,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            services: Vec::new(),
+            kill_policy: KillPolicy::default(),
+        }
+    }
+
+    /// How to stop commands.
+    pub fn kill_policy(&mut self, policy: KillPolicy) -> &mut Self {
+        self.kill_policy = policy;
+        self
+    }
+
+    /// Start `cmd` under supervision as `name`.
+    pub fn add(
+        &mut self,
+        name: &str,
+        cmd: &CommandBuilder,
+        restart: RestartPolicy,
+        log: &LogFile,
+    ) -> Result<()> {
+        if self.services.iter().any(|(x, _)| x.name == name) {
+            return Err(anyhow!("There is already a service called {name}"));
+        }
+        let mut rotating = RotatingLog::open(log)?;
+        // File I/O blocks, so keep it off the runtime's threads.
+        let (lines, received) = mpsc::channel::<String>();
+        let writer = std::thread::spawn(move || {
+            for line in received {
+                let _ = rotating.write_line(&line);
+            }
+        });
+        let mut cmd = cmd.clone();
+        cmd.inherit_execution_mode();
+        let secrets = cmd.secrets();
+        cmd.create_new_session().on_line(move |_, line| {
+            let _ = lines.send(redact::redact_with(line, &secrets));
+        });
+        let service = Arc::new(Service {
+            name: name.to_string(),
+            status: Mutex::new(ServiceStatus::Starting),
+            restarts: Mutex::new(0),
+            pid: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+            stop: Notify::new(),
+            writer: Mutex::new(Some(writer)),
+        });
+        let task = tokio::spawn(supervise(
+            service.clone(),
+            cmd,
+            restart,
+            self.kill_policy.clone(),
+        ));
+        self.services.push((service, task));
+        Ok(())
+    }
+
+    pub fn status(&self, name: &str) -> Option<ServiceStatus> {
+        self.find(name)
+            .and_then(|x| x.status.lock().ok().map(|x| x.clone()))
+    }
+
+    /// The status of every service, in the order they were added.
+    pub fn statuses(&self) -> Vec<(String, ServiceStatus)> {
+        self.services
+            .iter()
+            .filter_map(|(x, _)| Some((x.name.clone(), x.status.lock().ok()?.clone())))
+            .collect()
+    }
+
+    pub fn restarts(&self, name: &str) -> Option<u32> {
+        self.find(name)
+            .and_then(|x| x.restarts.lock().ok().map(|x| *x))
+    }
+
+    pub fn pid(&self, name: &str) -> Option<u32> {
+        self.find(name)
+            .and_then(|x| x.pid.lock().ok().and_then(|x| *x))
+    }
+
+    fn find(&self, name: &str) -> Option<&Arc<Service>> {
+        self.services
+            .iter()
+            .find(|(x, _)| x.name == name)
+            .map(|(x, _)| x)
+    }
+
+    /// Stop every service, last added first, waiting for each to exit and its log to be
+    /// written.
+    pub async fn stop_all(&mut self) -> Result<()> {
+        while let Some((service, task)) = self.services.pop() {
+            service.stopping.store(true, Ordering::SeqCst);
+            service.stop.notify_one();
+            task.await?;
+            let writer = service.writer.lock().ok().and_then(|mut x| x.take());
+            if let Some(writer) = writer {
+                let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop every service, as stop_all() does, and be done with the supervisor.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.stop_all().await
+    }
+
+    /// Wait for Ctrl-C, then stop everything.
+    pub async fn run_until_ctrl_c(&mut self) -> Result<()> {
+        // We stop the services ourselves, in order, rather than have the registry do it.
+        let forwarding = registry::forward_signals(false);
+        let result = tokio::signal::ctrl_c().await;
+        registry::forward_signals(forwarding);
+        result?;
+        println!(
+            "🛑 Interrupted - stopping {0} services",
+            self.services.len()
+        );
+        self.stop_all().await
+    }
+}
+
+impl Drop for Supervisor {
+    /// We can't wait here, so a thread of its own applies the kill policy to anything
+    /// still running. Call shutdown() to wait for services to stop.
+    fn drop(&mut self) {
+        let mut running = Vec::new();
+        for (service, _) in self.services.iter().rev() {
+            service.stopping.store(true, Ordering::SeqCst);
+            service.stop.notify_one();
+            if let Some(pid) = service.pid.lock().ok().and_then(|x| *x) {
+                if let Ok(pid) = i32::try_from(pid) {
+                    running.push(pid);
+                }
+            }
+        }
+        if running.is_empty() {
+            return;
+        }
+        let policy = self.kill_policy.clone();
+        std::thread::spawn(move || {
+            // Services are session leaders, so signal the whole group.
+            for pid in &running {
+                process::kill(-pid, policy.signal);
+            }
+            let deadline = Instant::now() + policy.grace;
+            while Instant::now() < deadline {
+                running.retain(|pid| process::kill(*pid, 0) == 0);
+                if running.is_empty() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            for pid in running {
+                process::kill(-pid, libc::SIGKILL);
+            }
+        });
+    }
+}
+
+async fn supervise(
+    service: Arc<Service>,
+    cmd: CommandBuilder,
+    restart: RestartPolicy,
+    kill_policy: KillPolicy,
+) {
+    let mut starts: u32 = 0;
+    loop {
+        if service.is_stopping() {
+            break;
+        }
+        if cmd.get_execution_mode().is_dry_run() {
+            if let Ok(line) = cmd.to_shell_command() {
+                println!("{line}  # 🔍 dry run");
+            }
+            service.set_status(ServiceStatus::Stopped);
+            break;
+        }
+        service.set_status(ServiceStatus::Starting);
+        starts += 1;
+        let mut proc = match cmd.spawn_quiet().await {
+            Ok(proc) => proc,
+            Err(e) => {
+                service.set_status(ServiceStatus::Failed(e.to_string()));
+                break;
+            }
+        };
+        if let Some(pid) = proc.child.id() {
+            service.set_status(ServiceStatus::Running { pid });
+        }
+        let status = match wait_or_stop(&service, &mut proc, &kill_policy).await {
+            Ok(status) => status,
+            Err(e) => {
+                service.set_status(ServiceStatus::Failed(e.to_string()));
+                break;
+            }
+        };
+        service.set_status(ServiceStatus::Exited {
+            code: status.code(),
+            signal: status.signal(),
+        });
+        if service.is_stopping() {
+            break;
+        }
+        let output = CommandOutput {
+            success: status.success(),
+            status_code: status.code().unwrap_or(-1),
+            signal: status.signal(),
+            ..CommandOutput::fake(status.success())
+        };
+        let policy = match &restart {
+            RestartPolicy::Never => break,
+            RestartPolicy::OnFailure(policy) if policy.should_retry(&output) => policy,
+            RestartPolicy::OnFailure(_) => break,
+            RestartPolicy::Always(policy) => policy,
+        };
+        if starts >= policy.max_attempts {
+            break;
+        }
+        let delay = policy.delay(starts);
+        println!(
+            "🔁 {0} exited with {1} - restarting in {delay:?}",
+            service.name, output.status_code
+        );
+        service.set_status(ServiceStatus::Restarting);
+        tokio::select! {
+            _ = time::sleep(delay) => (),
+            _ = service.stop.notified() => break,
+        }
+        if let Ok(mut restarts) = service.restarts.lock() {
+            *restarts += 1;
+        }
+    }
+    if service.is_stopping() {
+        service.set_status(ServiceStatus::Stopped);
+    }
+}
+
+/// Wait for `proc` to exit, or for someone to ask us to stop it.
+async fn wait_or_stop(
+    service: &Service,
+    proc: &mut ChildProcess,
+    kill_policy: &KillPolicy,
+) -> Result<std::process::ExitStatus> {
+    tokio::select! {
+        status = proc.wait_exit() => return status,
+        _ = service.stop.notified() => (),
+    }
+    proc.terminate(kill_policy).await
+}
@@ -0,0 +1,209 @@
+use crate::commands::CommandBuilder;
+use anyhow::{anyhow, Result};
+
+/// Where a command runs. Set one with `CommandBuilder::target()`; the builder then
+/// describes, and runs, the command wrapped as needed to get it there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ExecutionTarget {
+    #[default]
+    Local,
+    /// Through sudo, as `user` (root if None). Our environment overrides, other than PATH,
+    /// are passed through `env`; nothing we inherited goes with them, and sudo picks the
+    /// PATH. Unless `preserve_env` - in which case we pass sudo `-E` and the whole
+    /// environment goes with them.
+    Sudo {
+        user: Option<String>,
+        preserve_env: bool,
+    },
+    /// In a running docker container, via `docker exec`. Here and on other hosts, the
+    /// command gets the PATH it would get there, not ours.
+    Docker {
+        container: String,
+        user: Option<String>,
+    },
+    /// On another host, via ssh.
+    Ssh {
+        host: String,
+        user: Option<String>,
+        port: Option<u16>,
+        /// Extra arguments for ssh, eg. `-o StrictHostKeyChecking=no`.
+        options: Vec<String>,
+    },
+    /// On a GCE instance, via `gcloud compute ssh`.
+    GcloudSsh {
+        instance: String,
+        project: Option<String>,
+        zone: Option<String>,
+        tunnel_through_iap: bool,
+    },
+}
+
+impl ExecutionTarget {
+    pub fn sudo() -> Self {
+        ExecutionTarget::Sudo {
+            user: None,
+            preserve_env: false,
+        }
+    }
+
+    pub fn sudo_as(user: &str) -> Self {
+        ExecutionTarget::Sudo {
+            user: Some(user.to_string()),
+            preserve_env: false,
+        }
+    }
+
+    pub fn docker(container: &str) -> Self {
+        ExecutionTarget::Docker {
+            container: container.to_string(),
+            user: None,
+        }
+    }
+
+    pub fn ssh(host: &str) -> Self {
+        ExecutionTarget::Ssh {
+            host: host.to_string(),
+            user: None,
+            port: None,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn gcloud_ssh(project: &str, zone: &str, instance: &str) -> Self {
+        ExecutionTarget::GcloudSsh {
+            instance: instance.to_string(),
+            project: Some(project.to_string()),
+            zone: Some(zone.to_string()),
+            tunnel_through_iap: true,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        *self == ExecutionTarget::Local
+    }
+
+    /// A copy of `cmd`, rewritten to run locally a command which runs `cmd` on this
+    /// target. Secrets in `cmd` stay masked, wherever they end up in the new command line.
+    pub fn wrap(&self, cmd: &CommandBuilder) -> Result<CommandBuilder> {
+        let mut result = cmd.clone();
+        result.target(&ExecutionTarget::Local);
+        let name = cmd.get_cmd().ok_or(anyhow!("No command specified"))?;
+        let env_words = cmd.remote_env_words();
+        let mut words: Vec<String> = Vec::new();
+        match self {
+            ExecutionTarget::Local => return Ok(result),
+            ExecutionTarget::Sudo { user, preserve_env } => {
+                let env_words = cmd.privileged_env_words();
+                words.push("sudo".to_string());
+                if *preserve_env {
+                    words.push("-E".to_string());
+                }
+                if let Some(user) = user {
+                    words.extend(["-u".to_string(), user.clone()]);
+                }
+                words.push("--".to_string());
+                if !*preserve_env {
+                    if !env_words.is_empty() {
+                        words.push("env".to_string());
+                        words.extend(env_words);
+                    }
+                    result.reset_env();
+                }
+                words.push(name.to_string());
+                words.extend(cmd.get_args().iter().cloned());
+            }
+            ExecutionTarget::Docker { container, user } => {
+                words.extend(["docker".to_string(), "exec".to_string()]);
+                if cmd.get_input().is_some() {
+                    words.push("-i".to_string());
+                }
+                if cmd.is_pty() {
+                    words.push("-t".to_string());
+                }
+                if let Some(user) = user {
+                    words.extend(["-u".to_string(), user.clone()]);
+                }
+                if let Some(cwd) = cmd.get_cwd() {
+                    words.extend(["-w".to_string(), cwd.to_string()]);
+                }
+                let mut names: Vec<&String> = cmd
+                    .get_env()
+                    .iter()
+                    .flat_map(|x| x.keys())
+                    .filter(|x| *x != "PATH")
+                    .collect();
+                names.sort();
+                if cmd.inherits_all() {
+                    // Just the names: docker exec takes the values from its own
+                    // environment, which keeps them off its command line.
+                    for name in names {
+                        words.extend(["-e".to_string(), name.clone()]);
+                    }
+                    words.push(container.clone());
+                } else {
+                    words.push(container.clone());
+                    words.push("env".to_string());
+                    words.extend(env_words);
+                    result.reset_env();
+                }
+                words.push(name.to_string());
+                words.extend(cmd.get_args().iter().cloned());
+                result.clear_cwd();
+            }
+            ExecutionTarget::Ssh {
+                host,
+                user,
+                port,
+                options,
+            } => {
+                words.push("ssh".to_string());
+                if cmd.is_pty() {
+                    words.push("-tt".to_string());
+                }
+                if let Some(port) = port {
+                    words.extend(["-p".to_string(), port.to_string()]);
+                }
+                if let Some(user) = user {
+                    words.extend(["-l".to_string(), user.clone()]);
+                }
+                words.extend(options.iter().cloned());
+                words.extend([host.clone(), "--".to_string(), cmd.shell_command_line()?]);
+                result.reset_env().clear_cwd();
+            }
+            ExecutionTarget::GcloudSsh {
+                instance,
+                project,
+                zone,
+                tunnel_through_iap,
+            } => {
+                words.extend([
+                    "gcloud".to_string(),
+                    "compute".to_string(),
+                    "ssh".to_string(),
+                    instance.clone(),
+                ]);
+                if let Some(project) = project {
+                    words.extend(["--project".to_string(), project.clone()]);
+                }
+                if let Some(zone) = zone {
+                    words.extend(["--zone".to_string(), zone.clone()]);
+                }
+                if *tunnel_through_iap {
+                    words.push("--tunnel-through-iap".to_string());
+                }
+                words.push(format!("--command={0}", cmd.shell_command_line()?));
+                result.reset_env().clear_cwd();
+            }
+        }
+        // Whatever else we dropped, find sudo, ssh and so on with the PATH we were given.
+        if let Some(path) = cmd.get_env().and_then(|x| x.get("PATH")) {
+            if !result.get_env().is_some_and(|x| x.contains_key("PATH")) {
+                result.env_var("PATH", path);
+            }
+        }
+        let args: Vec<&str> = words[1..].iter().map(|x| x.as_str()).collect();
+        result.cmd(&words[0], &args);
+        result.mask_values(&cmd.secrets());
+        Ok(result)
+    }
+}
@@ -12,6 +12,16 @@ pub fn string_or_empty_from_u8(in_val: &[u8]) -> String {
     result.to_string()
 }
 
+/// Quote a string for a POSIX shell, leaving it alone if it needs no quoting.
+pub fn shell_quote(in_val: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !in_val.is_empty() && in_val.chars().all(safe) {
+        in_val.to_string()
+    } else {
+        format!("'{0}'", in_val.replace('\'', "'\\''"))
+    }
+}
+
 /// Get string from path
 pub fn string_from_path(in_path: &Path) -> Result<String> {
     Ok(in_path
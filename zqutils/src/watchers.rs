@@ -0,0 +1,181 @@
+use crate::capture::{OutputEvent, Transcript};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::time;
+
+/// How many lines we remember, so that waiting for a line that has already gone past works.
+const HISTORY_LINES: usize = 1000;
+
+/// How many lines a waiter can fall behind before it has to look in the history. Less
+/// than HISTORY_LINES, so that the lines it missed are still there.
+const PENDING_LINES: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    /// What we put in front of this stream's lines when we echo them.
+    pub fn prefix(&self) -> char {
+        match self {
+            OutputStream::Stdout => '>',
+            OutputStream::Stderr => '!',
+        }
+    }
+}
+
+type LineReceiver = broadcast::Receiver<Option<(OutputStream, String)>>;
+
+pub type LineCallback = Arc<dyn Fn(OutputStream, &str) + Send + Sync>;
+
+/// Callbacks to call with every line a command prints.
+#[derive(Clone, Default)]
+pub struct LineWatchers {
+    pub callbacks: Vec<LineCallback>,
+}
+
+impl fmt::Debug for LineWatchers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LineWatchers({0} callbacks)", self.callbacks.len())
+    }
+}
+
+impl LineWatchers {
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub fn add(&mut self, callback: LineCallback) {
+        self.callbacks.push(callback);
+    }
+
+    /// Call `callback` with every line which matches `pattern`.
+    pub fn add_match(&mut self, pattern: &str, callback: LineCallback) -> Result<()> {
+        let re = Regex::new(pattern)?;
+        self.callbacks.push(Arc::new(move |stream, line| {
+            if re.is_match(line) {
+                callback(stream, line)
+            }
+        }));
+        Ok(())
+    }
+}
+
+/// Where the output readers of one process send its lines: to the callbacks, to a short
+/// history, and to anyone waiting for a particular line.
+pub(crate) struct LineHub {
+    watchers: LineWatchers,
+    history: Mutex<VecDeque<(OutputStream, String)>>,
+    /// None means that every stream has ended.
+    sender: broadcast::Sender<Option<(OutputStream, String)>>,
+    open_streams: AtomicUsize,
+    /// Every line, with when it came, if we were asked to record them.
+    transcript: Option<Mutex<Transcript>>,
+    started: Instant,
+}
+
+impl LineHub {
+    pub(crate) fn new(watchers: &LineWatchers, streams: usize, record: bool) -> Arc<Self> {
+        let (sender, _) = broadcast::channel(PENDING_LINES);
+        Arc::new(Self {
+            watchers: watchers.clone(),
+            history: Mutex::new(VecDeque::new()),
+            sender,
+            open_streams: AtomicUsize::new(streams),
+            transcript: record.then(|| Mutex::new(Transcript::new())),
+            started: Instant::now(),
+        })
+    }
+
+    pub(crate) fn publish(&self, stream: OutputStream, line: &str) {
+        for callback in &self.watchers.callbacks {
+            callback(stream, line);
+        }
+        if let Ok(mut history) = self.history.lock() {
+            if history.len() >= HISTORY_LINES {
+                history.pop_front();
+            }
+            history.push_back((stream, line.to_string()));
+            // Also under the lock, so that the transcript is in the order we read lines.
+            if let Some(Ok(mut transcript)) = self.transcript.as_ref().map(|x| x.lock()) {
+                transcript.events.push(OutputEvent {
+                    elapsed: self.started.elapsed(),
+                    stream,
+                    line: line.to_string(),
+                });
+            }
+            // Under the lock, so that wait_for_line() sees every line exactly once.
+            let _ = self.sender.send(Some((stream, line.to_string())));
+        }
+    }
+
+    pub(crate) fn close_stream(&self) {
+        if self.open_streams.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Ok(_history) = self.history.lock() {
+                let _ = self.sender.send(None);
+            }
+        }
+    }
+
+    /// What we recorded, once the streams have closed.
+    pub(crate) fn take_transcript(&self) -> Option<Transcript> {
+        let transcript = self.transcript.as_ref()?;
+        transcript.lock().ok().map(|mut x| std::mem::take(&mut *x))
+    }
+
+    /// A line matching `re` that we have already seen, or else a receiver for the lines
+    /// after those.
+    fn seen_or_subscribe(&self, re: &Regex) -> Result<Result<String, LineReceiver>> {
+        let history = self
+            .history
+            .lock()
+            .map_err(|_| anyhow!("Line history lock is poisoned"))?;
+        if let Some((_, line)) = history.iter().find(|(_, line)| re.is_match(line)) {
+            return Ok(Ok(line.clone()));
+        }
+        if self.open_streams.load(Ordering::SeqCst) == 0 {
+            return Err(anyhow!("Output ended without a line matching {re}"));
+        }
+        Ok(Err(self.sender.subscribe()))
+    }
+
+    /// Wait for a line matching `re`, and return it.
+    pub(crate) async fn wait_for_line(&self, re: &Regex, timeout: Duration) -> Result<String> {
+        let mut receiver = match self.seen_or_subscribe(re)? {
+            Ok(line) => return Ok(line),
+            Err(receiver) => receiver,
+        };
+        let wait = async {
+            loop {
+                match receiver.recv().await {
+                    Ok(Some((_, line))) if re.is_match(&line) => return Ok(line),
+                    Ok(Some(_)) => (),
+                    // The lines we missed are in the history, unless we are a long way
+                    // behind.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        match self.seen_or_subscribe(re)? {
+                            Ok(line) => return Ok(line),
+                            Err(next) => receiver = next,
+                        }
+                    }
+                    Ok(None) | Err(broadcast::error::RecvError::Closed) => {
+                        return Err(anyhow!("Output ended without a line matching {re}"))
+                    }
+                }
+            }
+        };
+        time::timeout(timeout, wait)
+            .await
+            .map_err(|_| anyhow!("Timed out after {timeout:?} waiting for a line matching {re}"))?
+    }
+}
@@ -1,4 +1,25 @@
-use zqutils::commands::CommandBuilder;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use zqutils::audit::{AuditEvent, AuditLog, AuditReader};
+use zqutils::capture::{CaptureLimit, Transcript};
+use zqutils::commands::{CommandBuilder, CommandOutput, KillPolicy, RetryPolicy};
+use zqutils::containers;
+use zqutils::errors::CommandError;
+use zqutils::limits::ResourceLimits;
+use zqutils::manifest::Manifest;
+use zqutils::mode::{self, ExecutionMode, Recording};
+use zqutils::packages::PackageManager;
+use zqutils::parallel::{JobResult, Parallel};
+use zqutils::pipeline::Pipeline;
+use zqutils::redact;
+use zqutils::registry;
+use zqutils::runner::{self, CommandRunner, FakeRunner};
+use zqutils::script::{self, Context, StepStatus};
+use zqutils::supervisor::{LogFile, RestartPolicy, ServiceStatus, Supervisor};
+use zqutils::target::ExecutionTarget;
+use zqutils::watchers::OutputStream;
 
 #[tokio::test]
 async fn test_no_color() {
@@ -22,3 +43,1380 @@ async fn test_color() {
         .await
         .expect("Error executing command");
 }
+
+#[tokio::test]
+async fn test_timeout() {
+    let result = CommandBuilder::new()
+        .cmd("sleep", &["10"])
+        .timeout(Duration::from_millis(200))
+        .ignore_failures()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert!(result.timed_out);
+    assert!(!result.success);
+    assert_eq!(result.signal, Some(libc::SIGTERM));
+
+    // The shell's sleep outlives it and holds the output open; we shouldn't wait for it.
+    let started = std::time::Instant::now();
+    let mut builder = CommandBuilder::new();
+    builder
+        .cmd("sh", &["-c", "echo started; sleep 6; true"])
+        .timeout(Duration::from_millis(300))
+        .ignore_failures();
+    let result = builder
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(result.timed_out);
+    assert_eq!(result.stdout, b"started\n");
+    let result = builder
+        .tee_output()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert!(result.timed_out);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_tee() {
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "echo hello; echo oops >&2"])
+        .tee_output()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.stdout, b"hello\n");
+    assert_eq!(result.stderr, b"oops\n");
+}
+
+#[tokio::test]
+async fn test_retry() {
+    let policy = RetryPolicy::new(3)
+        .initial_delay(Duration::from_millis(10))
+        .on_stderr("flaky")
+        .expect("Bad regex");
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "echo flaky >&2; exit 2"])
+        .retry(policy.clone())
+        .ignore_failures()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.attempts, 3);
+    assert_eq!(result.status_code, 2);
+
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "echo solid >&2; exit 2"])
+        .retry(policy)
+        .ignore_failures()
+        .log_output()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.attempts, 1);
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+    let result = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("sort", &[]))
+        .stage(CommandBuilder::new().cmd("head", &["-n", "1"]))
+        .input(b"pear\napple\nquince\n")
+        .run()
+        .await
+        .expect("Error executing pipeline");
+    assert!(result.output.success);
+    assert_eq!(result.output.sanitise_stdout().unwrap(), "apple");
+
+    let result = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("sh", &["-c", "exit 3"]))
+        .stage(CommandBuilder::new().cmd("cat", &[]))
+        .ignore_failures()
+        .run()
+        .await
+        .expect("Error executing pipeline");
+    assert!(!result.output.success);
+    assert_eq!(result.output.status_code, 3);
+    let codes: Vec<i32> = result.stages.iter().map(|x| x.status_code).collect();
+    assert_eq!(codes, vec![3, 0]);
+    let err = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("sh", &["-c", "echo broken >&2; exit 3"]))
+        .stage(CommandBuilder::new().cmd("cat", &[]))
+        .run()
+        .await
+        .err()
+        .expect("Pipeline should fail");
+    match err.downcast_ref::<CommandError>() {
+        Some(CommandError::Exit {
+            command,
+            code,
+            stderr_tail,
+            ..
+        }) => {
+            assert_eq!(command, "sh -c echo broken >&2; exit 3 | cat");
+            assert_eq!(*code, 3);
+            assert_eq!(stderr_tail, "broken");
+        }
+        other => panic!("Unexpected error {other:?}"),
+    }
+
+    // A stage that stops reading early is fine; input that can't be read is not.
+    let result = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("head", &["-c", "5"]))
+        .stage(CommandBuilder::new().cmd("cat", &[]))
+        .input(&vec![b'x'; 1 << 20])
+        .run()
+        .await
+        .expect("Error executing pipeline");
+    assert_eq!(result.output.sanitise_stdout().unwrap(), "xxxxx");
+    let err = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("cat", &[]))
+        .input_file(std::path::Path::new("/nonexistent/zqutils-input"))
+        .run()
+        .await
+        .err()
+        .expect("Pipeline should fail");
+    assert!(err.to_string().contains("Cannot open input"), "{err}");
+
+    // Nor does a stage's background descendant keep us waiting.
+    let started = std::time::Instant::now();
+    let result = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("cat", &[]))
+        .stage(CommandBuilder::new().cmd("sh", &["-c", "cat; sleep 5 &"]))
+        .input(b"hi\n")
+        .run()
+        .await
+        .expect("Error executing pipeline");
+    assert_eq!(result.output.sanitise_stdout().unwrap(), "hi");
+    assert!(started.elapsed() < Duration::from_secs(4));
+
+    // If a stage won't start, the ones before it are stopped.
+    let err = Pipeline::new()
+        .stage(CommandBuilder::new().cmd("sleep", &["31"]))
+        .stage(CommandBuilder::new().cmd("/nonexistent/zqutils-test", &[]))
+        .run()
+        .await
+        .err()
+        .expect("Pipeline should fail");
+    assert!(matches!(
+        err.downcast_ref::<CommandError>(),
+        Some(CommandError::Spawn { .. })
+    ));
+    assert!(!registry::tracked()
+        .iter()
+        .any(|x| x.description == "sleep 31"));
+}
+
+#[tokio::test]
+async fn test_fake_runner() {
+    let fake = Arc::new(FakeRunner::new());
+    fake.respond(
+        "docker",
+        &["container", "inspect", "-f", "{{.State.Status}}", "node0"],
+        CommandOutput::fake_with_stdout(true, "running\n"),
+    )
+    .respond_any("docker", CommandOutput::fake(false));
+    let runner: Arc<dyn CommandRunner> = fake.clone();
+    assert!(
+        containers::is_container_status_running_with(&runner, "node0")
+            .await
+            .expect("Fake failed")
+    );
+    assert!(
+        !containers::is_container_status_running_with(&runner, "node1")
+            .await
+            .expect("Fake failed")
+    );
+    containers::kill_container_with(&runner, "node1")
+        .await
+        .expect("Fake failed");
+    let calls: Vec<String> = fake.calls().iter().map(|x| x.args[0].clone()).collect();
+    assert_eq!(calls, vec!["container", "container", "kill", "rm"]);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let path = std::env::temp_dir().join(format!("zqutils-audit-{0}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let log = AuditLog::open(&path).expect("Cannot open audit log");
+    let _ = CommandBuilder::new()
+        .cmd("sh", &["-c", "echo hello; exit 4"])
+        .env_var("AUDITED", "yes")
+        .audit_log(log)
+        .ignore_failures()
+        .run()
+        .await
+        .expect("Error executing command");
+    let reader = AuditReader::load(&path).expect("Cannot read audit log");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(reader.entries.len(), 2);
+    assert_eq!(reader.entries[0].event, AuditEvent::Spawn);
+    let done = reader.completed().next().expect("No completion");
+    assert_eq!(done.exit_code, Some(4));
+    assert_eq!(done.stdout.as_deref(), Some("hello\n"));
+    assert_eq!(done.env.get("AUDITED").map(|x| x.as_str()), Some("yes"));
+    assert_eq!(reader.diff(&reader), Vec::<String>::new());
+
+    // Commands on a target are logged as we ran them.
+    let log = AuditLog::open(&path).expect("Cannot open audit log");
+    CommandBuilder::new()
+        .cmd("true", &[])
+        .secret_env_var("TOKEN", "s3cret")
+        .path_prepend(&install_stubs())
+        .target(&ExecutionTarget::sudo())
+        .audit_log(log)
+        .run()
+        .await
+        .expect("Error executing command");
+    let reader = AuditReader::load(&path).expect("Cannot read audit log");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(reader.entries[0].cmd, "sudo");
+    assert_eq!(
+        reader.entries[0].args,
+        vec!["--", "env", "TOKEN=***", "true"]
+    );
+}
+
+#[tokio::test]
+async fn test_redaction() {
+    let mut cmd = CommandBuilder::new();
+    cmd.cmd("sh", &["-c", "echo token=$1 >&2; exit 1", "sh"])
+        .secret_arg("hunter2");
+    assert_eq!(
+        cmd.describe_command().unwrap(),
+        "[]$ sh -c echo token=$1 >&2; exit 1 sh ***"
+    );
+    let err = cmd.run().await.expect_err("Command should fail");
+    assert!(!format!("{err}").contains("hunter2"));
+    assert!(format!("{err}").contains("token=***"));
+
+    redact::register_secret("swordfish");
+    assert_eq!(redact::redact("pass=swordfish"), "pass=***");
+}
+
+#[test]
+fn test_shell_rendering() {
+    let mut cmd = CommandBuilder::new();
+    cmd.cmd("sudo", &["bash", "-c", "echo \"it's here\""])
+        .cwd("/tmp/my dir")
+        .env_var("DEBIAN_FRONTEND", "noninteractive");
+    assert_eq!(
+        cmd.to_shell_command().unwrap(),
+        "(cd '/tmp/my dir' && env DEBIAN_FRONTEND=noninteractive sudo bash -c 'echo \"it'\\''s here\"')"
+    );
+    let mut pipeline = Pipeline::new();
+    pipeline
+        .stage(CommandBuilder::new().cmd("ls", &[]))
+        .stage(CommandBuilder::new().cmd("grep", &["a b", ""]));
+    assert_eq!(pipeline.to_shell_command().unwrap(), "ls | grep 'a b' ''");
+}
+
+#[tokio::test]
+async fn test_input() {
+    let result = CommandBuilder::new()
+        .cmd("od", &["-An", "-tx1"])
+        .input_bytes(&[0, 255, 10])
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.sanitise_stdout().unwrap(), "00 ff 0a");
+
+    let result = CommandBuilder::new()
+        .cmd("wc", &["-c"])
+        .input_reader(&b"twelve bytes"[..])
+        .tee_output()
+        .run()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.sanitise_stdout().unwrap(), "12");
+
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "cat >/dev/null; exit 5"])
+        .run_logged_with_input("hello")
+        .await;
+    assert!(result.is_err());
+
+    let err = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &["-c", "read pw; echo \"bad password $pw\" >&2; exit 1"],
+        )
+        .input_bytes(b"hunter2\n")
+        .secret_input()
+        .run_for_output()
+        .await
+        .expect_err("Command should fail")
+        .to_string();
+    assert!(err.contains("bad password ***"), "{err}");
+    assert!(!err.contains("hunter2"), "{err}");
+}
+
+#[tokio::test]
+async fn test_wait_for_line() {
+    let seen = Arc::new(AtomicUsize::new(0));
+    let seen_copy = seen.clone();
+    let mut child = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &[
+                "-c",
+                "echo starting; sleep 0.2; echo ready on 4201 >&2; sleep 5",
+            ],
+        )
+        .on_match("^ready", move |stream, _| {
+            assert_eq!(stream, OutputStream::Stderr);
+            seen_copy.fetch_add(1, Ordering::SeqCst);
+        })
+        .expect("Bad regex")
+        .spawn_logged()
+        .await
+        .expect("Error spawning command");
+    let line = child
+        .wait_for_line("ready on [0-9]+", Duration::from_secs(5))
+        .await
+        .expect("Never got ready");
+    assert_eq!(line, "ready on 4201");
+    assert_eq!(seen.load(Ordering::SeqCst), 1);
+    assert!(child
+        .wait_for_line("never", Duration::from_millis(100))
+        .await
+        .is_err());
+    child.child.kill().await.expect("Cannot kill child");
+
+    // A burst of output after the line we want doesn't hide it.
+    let mut child = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &[
+                "-c",
+                "out=$(echo ready; seq 1 600); sleep 0.2; echo \"$out\"; sleep 5",
+            ],
+        )
+        .spawn_logged()
+        .await
+        .expect("Error spawning command");
+    let line = child
+        .wait_for_line("^ready$", Duration::from_secs(5))
+        .await
+        .expect("Missed the line");
+    assert_eq!(line, "ready");
+    child.child.kill().await.expect("Cannot kill child");
+}
+
+#[tokio::test]
+async fn test_limits_and_usage() {
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "ulimit -n; ulimit -c"])
+        .limits(ResourceLimits {
+            open_files: Some(64),
+            core_dump: Some(0),
+            nice: Some(5),
+            ..Default::default()
+        })
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.sanitise_stdout().unwrap(), "64\n0");
+    let usage = result.usage.expect("No usage recorded");
+    assert!(usage.max_rss_kb > 0);
+    assert!(usage.wall_time > Duration::ZERO);
+
+    // Once we have waited, tokio should know the child has gone too.
+    let mut child = CommandBuilder::new()
+        .cmd("sh", &["-c", "exit 4"])
+        .spawn_logged()
+        .await
+        .expect("Error spawning command");
+    assert_eq!(child.wait().await.unwrap().code(), Some(4));
+    assert_eq!(child.child.try_wait().unwrap().unwrap().code(), Some(4));
+    child.child.kill().await.expect("Cannot kill child");
+}
+
+#[tokio::test]
+async fn test_supervisor() {
+    let dir = std::env::temp_dir();
+    let flaky_log = dir.join(format!("zqutils-flaky-{0}.log", std::process::id()));
+    let sleepy_log = dir.join(format!("zqutils-sleepy-{0}.log", std::process::id()));
+    let policy = RetryPolicy::new(3).initial_delay(Duration::from_millis(10));
+    let mut supervisor = Supervisor::new();
+    supervisor
+        .add(
+            "flaky",
+            CommandBuilder::new()
+                .cmd("sh", &["-c", "echo started $0; exit 1"])
+                .secret_arg("hunter2"),
+            RestartPolicy::OnFailure(policy.clone()),
+            &LogFile::new(&flaky_log),
+        )
+        .expect("Cannot add flaky");
+    supervisor
+        .add(
+            "sleepy",
+            CommandBuilder::new().cmd("sleep", &["30"]),
+            RestartPolicy::Always(policy),
+            &LogFile::new(&sleepy_log),
+        )
+        .expect("Cannot add sleepy");
+    for _ in 0..100 {
+        if supervisor.restarts("flaky") == Some(2)
+            && matches!(
+                supervisor.status("flaky"),
+                Some(ServiceStatus::Exited { .. })
+            )
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        supervisor.status("flaky"),
+        Some(ServiceStatus::Exited {
+            code: Some(1),
+            signal: None
+        })
+    );
+    assert!(matches!(
+        supervisor.status("sleepy"),
+        Some(ServiceStatus::Running { .. })
+    ));
+    let pid = supervisor.pid("sleepy").expect("sleepy has no pid");
+    supervisor.shutdown().await.expect("Cannot stop services");
+    assert!(!zqutils::process::is_running(&pid).unwrap_or(false));
+
+    // Dropping a supervisor stops its services without waiting for them, killing those
+    // which ignore the signal.
+    let mut supervisor = Supervisor::new();
+    supervisor
+        .kill_policy(KillPolicy {
+            signal: libc::SIGTERM,
+            grace: Duration::from_millis(300),
+        })
+        .add(
+            "stubborn",
+            CommandBuilder::new().cmd("sh", &["-c", "trap '' TERM; sleep 30"]),
+            RestartPolicy::Never,
+            &LogFile::new(&sleepy_log),
+        )
+        .expect("Cannot add stubborn");
+    let mut pid = None;
+    for _ in 0..100 {
+        pid = supervisor.pid("stubborn");
+        if pid.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let pid = pid.expect("stubborn has no pid");
+    let started = std::time::Instant::now();
+    drop(supervisor);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    for _ in 0..100 {
+        if !zqutils::process::is_running(&pid).unwrap_or(false) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(!zqutils::process::is_running(&pid).unwrap_or(false));
+    let logged = std::fs::read_to_string(&flaky_log).expect("Cannot read log");
+    assert_eq!(logged, "started ***\nstarted ***\nstarted ***\n");
+    let _ = std::fs::remove_file(flaky_log);
+    let _ = std::fs::remove_file(sleepy_log);
+}
+
+#[tokio::test]
+async fn test_command_errors() {
+    let err = CommandBuilder::new()
+        .cmd("/nonexistent/zqutils-test", &[])
+        .run()
+        .await
+        .expect_err("Command should not start");
+    assert!(matches!(
+        err.downcast_ref::<CommandError>(),
+        Some(CommandError::Spawn { .. })
+    ));
+
+    let err = CommandBuilder::new()
+        .cmd("sh", &["-c", "echo oops >&2; exit 3"])
+        .cwd("/tmp")
+        .run()
+        .await
+        .expect_err("Command should fail");
+    let err = err.downcast_ref::<CommandError>().expect("Untyped error");
+    assert_eq!(err.exit_code(), Some(3));
+    assert_eq!(err.cwd(), Some("/tmp"));
+    assert_eq!(err.stderr_tail(), "oops");
+
+    let err = CommandBuilder::new()
+        .cmd("sh", &["-c", "kill -TERM $$"])
+        .run()
+        .await
+        .expect_err("Command should be killed");
+    let err = err.downcast_ref::<CommandError>().expect("Untyped error");
+    assert_eq!(err.signal(), Some(libc::SIGTERM));
+}
+
+#[tokio::test]
+async fn test_pty() {
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "test -t 0 && test -t 1 && echo terminal"])
+        .pty()
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.sanitise_stdout().unwrap(), "terminal");
+
+    // A grandchild holding the terminal open doesn't keep us waiting.
+    let started = std::time::Instant::now();
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "trap '' HUP; echo started; sleep 6 & exit 0"])
+        .pty()
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.sanitise_stdout().unwrap(), "started");
+    assert!(started.elapsed() < Duration::from_secs(4));
+
+    let mut session = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &["-c", "printf 'Password: '; read pw; echo \"got $pw\""],
+        )
+        .spawn_session()
+        .await
+        .expect("Cannot spawn session");
+    session
+        .expect("Password: $", Duration::from_secs(5))
+        .await
+        .expect("No prompt");
+    session
+        .send_secret_line("hunter2")
+        .await
+        .expect("Cannot answer prompt");
+    session
+        .expect("got .*", Duration::from_secs(5))
+        .await
+        .expect("No reply");
+    let output = session.wait().await.expect("Session failed");
+    let transcript = output.sanitise_stdout().unwrap();
+    assert!(transcript.contains("got ***"));
+    assert!(!transcript.contains("hunter2"));
+}
+
+#[tokio::test]
+async fn test_parallel() {
+    let mut parallel = Parallel::new(2);
+    for idx in 0..4 {
+        parallel.add(
+            &format!("job-{idx}"),
+            CommandBuilder::new().cmd("sh", &["-c", &format!("sleep 0.2; echo {idx}")]),
+        );
+    }
+    let started = std::time::Instant::now();
+    let result = parallel.run().await.expect("Parallel run failed");
+    assert!(started.elapsed() < Duration::from_millis(750));
+    let outputs: Vec<String> = result
+        .results
+        .iter()
+        .map(|x| x.output().unwrap().sanitise_stdout().unwrap())
+        .collect();
+    assert_eq!(outputs, vec!["0", "1", "2", "3"]);
+    assert_eq!(result.summary.succeeded, 4);
+
+    let mut parallel = Parallel::new(1);
+    parallel
+        .add("bad", CommandBuilder::new().cmd("false", &[]))
+        .add("good", CommandBuilder::new().cmd("true", &[]))
+        .fail_fast()
+        .ignore_failures();
+    let result = parallel.run().await.expect("Parallel run failed");
+    assert!(matches!(result.results[1], JobResult::Skipped));
+    assert_eq!(result.failures(), vec!["bad"]);
+    assert!(Parallel::new(2)
+        .add("bad", CommandBuilder::new().cmd("false", &[]))
+        .run()
+        .await
+        .is_err());
+}
+
+/// Write stand-ins for sudo, docker, ssh, gcloud and dpkg-query, and return the
+/// directory to put on the PATH. The docker one prints its arguments; sudo, ssh and
+/// gcloud run the command they are given locally; dpkg-query has everything installed
+/// except gone, which was removed, and missing, which it has never heard of.
+fn install_stubs() -> String {
+    use std::os::unix::fs::PermissionsExt as _;
+    let dir = std::env::temp_dir().join(format!("zqutils-stubs-{0}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Cannot create stub directory");
+    let stubs = [
+        ("sudo", "while [ \"$1\" != -- ]; do shift; done; shift; exec \"$@\""),
+        ("docker", "for arg in \"$@\"; do echo \"[$arg]\"; done"),
+        ("ssh", "while [ \"$1\" != -- ]; do shift; done; exec sh -c \"$2\""),
+        (
+            "gcloud",
+            "for arg in \"$@\"; do case \"$arg\" in --command=*) exec sh -c \"${arg#--command=}\";; esac; done",
+        ),
+        (
+            "dpkg-query",
+            "for arg in \"$@\"; do case \"$arg\" in -*) ;; gone) echo deinstall;; missing) exit 1;; *) echo installed;; esac; done",
+        ),
+    ];
+    for (name, body) in stubs {
+        // Other tests may be running the stubs, so replace them rather than rewriting them.
+        let path = dir.join(name);
+        let new_path = dir.join(format!(".{name}.{0:?}", std::thread::current().id()));
+        std::fs::write(&new_path, format!("#!/bin/sh\n{body}\n")).expect("Cannot write stub");
+        std::fs::set_permissions(&new_path, std::fs::Permissions::from_mode(0o755))
+            .expect("Cannot make stub executable");
+        std::fs::rename(&new_path, &path).expect("Cannot install stub");
+    }
+    dir.display().to_string()
+}
+
+#[tokio::test]
+async fn test_execution_targets() {
+    let stubs = install_stubs();
+    let mut cmd = CommandBuilder::new();
+    cmd.cmd("sh", &["-c", "echo \"$GREETING, it's $(pwd)\""])
+        .env_var("GREETING", "hello world")
+        .path_prepend(&stubs)
+        .cwd("/tmp")
+        .silent();
+    let targets = [
+        ExecutionTarget::Local,
+        ExecutionTarget::sudo(),
+        ExecutionTarget::ssh("remote"),
+        ExecutionTarget::gcloud_ssh("proj", "zone", "vm"),
+    ];
+    for target in targets {
+        let result = cmd
+            .clone()
+            .target(&target)
+            .run_for_output()
+            .await
+            .expect("Error executing command");
+        assert_eq!(
+            result.sanitise_stdout().unwrap(),
+            "hello world, it's /tmp",
+            "on {target:?}"
+        );
+    }
+
+    let mut docker = CommandBuilder::new();
+    docker
+        .cmd("ls", &["-l"])
+        .secret_env_var("TOKEN", "s3cret")
+        .path_prepend(&stubs)
+        .cwd("/srv")
+        .target(&ExecutionTarget::docker("node-1"));
+    let result = docker.run_for_output().await.expect("Error running docker");
+    assert_eq!(
+        result.sanitise_stdout().unwrap(),
+        "[exec]\n[-w]\n[/srv]\n[-e]\n[TOKEN]\n[node-1]\n[ls]\n[-l]"
+    );
+
+    // Only explicit overrides other than PATH go through sudo.
+    let mut root = CommandBuilder::new();
+    root.cmd("apt-get", &["update"])
+        .env_var("DEBIAN_FRONTEND", "noninteractive")
+        .path_append("/opt/extra/bin")
+        .target(&ExecutionTarget::sudo());
+    let rendered = root.to_shell_command().unwrap();
+    let (_, inside) = rendered.split_once("sudo --").unwrap();
+    assert_eq!(inside, " env DEBIAN_FRONTEND=noninteractive apt-get update");
+    root.env_allow(&["HOME"]);
+    let rendered = root.to_shell_command().unwrap();
+    let (_, inside) = rendered.split_once("sudo --").unwrap();
+    assert_eq!(
+        inside,
+        " env -i DEBIAN_FRONTEND=noninteractive apt-get update"
+    );
+
+    let mut ssh = CommandBuilder::new();
+    ssh.cmd("login", &[])
+        .secret_arg("s3cret")
+        .target(&ExecutionTarget::ssh("remote"));
+    assert_eq!(ssh.to_shell_command().unwrap(), "ssh remote -- 'login ***'");
+    // Our PATH finds ssh, but doesn't go with it.
+    ssh.path_prepend("/opt/local/bin");
+    let rendered = ssh.to_shell_command().unwrap();
+    assert!(
+        rendered.starts_with("env PATH=/opt/local/bin:"),
+        "{rendered}"
+    );
+    assert!(
+        rendered.ends_with(" ssh remote -- 'login ***'"),
+        "{rendered}"
+    );
+}
+
+#[tokio::test]
+async fn test_env_modes() {
+    let names = |output: CommandOutput| -> Vec<String> {
+        let mut names: Vec<String> = output
+            .sanitise_stdout()
+            .unwrap()
+            .lines()
+            .filter_map(|x| x.split_once('=').map(|(k, _)| k.to_string()))
+            .collect();
+        names.sort();
+        names
+    };
+    let mut cmd = CommandBuilder::new();
+    cmd.cmd("/usr/bin/env", &[]).silent();
+
+    let result = cmd
+        .clone()
+        .env_clear()
+        .env_var("FOO", "bar")
+        .path_prepend("/opt/bin")
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(result.sanitise_stdout().unwrap().contains("PATH=/opt/bin"));
+    assert_eq!(names(result), vec!["FOO", "PATH"]);
+
+    let result = cmd
+        .clone()
+        .env_allow(&["PATH", "HOME"])
+        .env_remove("HOME")
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(names(result), vec!["PATH"]);
+
+    let result = cmd
+        .clone()
+        .env_var("FOO", "bar")
+        .env_remove("FOO")
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(!names(result).contains(&"FOO".to_string()));
+
+    assert_eq!(
+        CommandBuilder::new()
+            .cmd("true", &[])
+            .env_clear()
+            .env_var("A", "b")
+            .to_shell_command()
+            .unwrap(),
+        "env -i A=b true"
+    );
+}
+
+#[tokio::test]
+async fn test_typed_output() {
+    let parsed: std::collections::HashMap<String, i32> = CommandBuilder::new()
+        .cmd("echo", &["{\"a\": 1, \"b\": 2}"])
+        .run_json()
+        .await
+        .expect("Cannot parse JSON");
+    assert_eq!(parsed.get("b"), Some(&2));
+    let parsed: Vec<String> = CommandBuilder::new()
+        .cmd("printf", &["- x\\n- y\\n"])
+        .run_yaml()
+        .await
+        .expect("Cannot parse YAML");
+    assert_eq!(parsed, vec!["x", "y"]);
+    let lines = CommandBuilder::new()
+        .cmd("printf", &["one  \\n\\ntwo\\n"])
+        .run_lines()
+        .await
+        .expect("Cannot get lines");
+    assert_eq!(lines, vec!["one", "two"]);
+
+    let err = CommandBuilder::new()
+        .cmd("printf", &["{\\n  \"a\": nope\\n}\\n"])
+        .run_json::<serde_json::Value>()
+        .await
+        .expect_err("Output should not parse");
+    let message = err.to_string();
+    assert!(message.contains("printf"));
+    assert!(message.contains("2 |   \"a\": nope"));
+    assert!(CommandBuilder::new()
+        .cmd("false", &[])
+        .ignore_failures()
+        .run_lines()
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_capture_limit() {
+    let result = CommandBuilder::new()
+        .cmd("seq", &["1", "100000"])
+        .capture_limit(CaptureLimit::new(1000))
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(result.truncated);
+    let stdout = result.sanitise_stdout().unwrap();
+    assert!(stdout.starts_with("1\n2\n3\n"));
+    assert!(stdout.ends_with("99999\n100000"));
+    assert!(stdout.contains("bytes truncated"));
+    assert!(stdout.len() < 1100);
+    let spill = result.stdout_spill.expect("Output was not spilled");
+    let mode = std::fs::metadata(&spill).unwrap().permissions();
+    assert_eq!(
+        std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+        0o600
+    );
+    let everything = std::fs::read_to_string(&spill).expect("Cannot read spill file");
+    assert_eq!(everything.lines().count(), 100000);
+    assert!(result.stderr_spill.is_none());
+    let _ = std::fs::remove_file(spill);
+
+    // Retries don't leave the spills of earlier attempts behind.
+    let spills = || {
+        let prefix = format!("zqutils-{0}-", std::process::id());
+        std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|x| x.ok()?.file_name().into_string().ok())
+            .filter(|x| x.starts_with(&prefix) && x.contains("-Stderr-"))
+            .count()
+    };
+    let result = CommandBuilder::new()
+        .cmd("sh", &["-c", "seq 1 5000 >&2; exit 1"])
+        .capture_limit(CaptureLimit::new(1000))
+        .retry(RetryPolicy::new(3).initial_delay(Duration::from_millis(10)))
+        .ignore_failures()
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert_eq!(result.attempts, 3);
+    assert_eq!(spills(), 1);
+    let _ = std::fs::remove_file(result.stderr_spill.unwrap());
+
+    let result = CommandBuilder::new()
+        .cmd("echo", &["short"])
+        .capture_limit(CaptureLimit::new(1000))
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(!result.truncated);
+    assert!(result.stdout_spill.is_none());
+
+    // A very long line is neither kept whole nor handed whole to watchers.
+    let result = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &[
+                "-c",
+                "head -c 5000000 /dev/zero | tr '\\0' a; echo; echo after",
+            ],
+        )
+        .capture_limit(CaptureLimit {
+            max_bytes: 1000,
+            head_bytes: 500,
+            spill_to_disk: false,
+        })
+        .record_transcript()
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    assert!(result.truncated);
+    assert!(result.stdout.len() < 1100);
+    let events = result.transcript.expect("No transcript recorded").events;
+    assert_eq!(events.len(), 2);
+    assert!(events[0].line.len() <= 64 * 1024);
+    assert_eq!(events[1].line, "after");
+}
+
+#[tokio::test]
+async fn test_transcript() {
+    let result = CommandBuilder::new()
+        .cmd(
+            "sh",
+            &[
+                "-c",
+                "echo one; sleep 0.1; echo two $0 >&2; sleep 0.1; echo three",
+            ],
+        )
+        .secret_arg("hunter2")
+        .record_transcript()
+        .run_for_output()
+        .await
+        .expect("Error executing command");
+    let transcript = result.transcript.expect("No transcript recorded");
+    let events: Vec<(OutputStream, &str)> = transcript
+        .events
+        .iter()
+        .map(|x| (x.stream, x.line.as_str()))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (OutputStream::Stdout, "one"),
+            (OutputStream::Stderr, "two ***"),
+            (OutputStream::Stdout, "three")
+        ]
+    );
+    assert!(transcript.events[2].elapsed >= Duration::from_millis(200));
+    let rendered = transcript.render();
+    assert!(rendered.lines().nth(1).unwrap().ends_with("s] !two ***"));
+    assert!(!transcript.to_json().unwrap().contains("hunter2"));
+    let parsed: Transcript =
+        serde_json::from_str(&transcript.to_json().unwrap()).expect("Cannot parse JSON");
+    assert_eq!(parsed, transcript);
+}
+
+/// Run by test_signal_forwarding, in a process of its own: start two children, one of
+/// which ignores SIGTERM (once it says so), and wait to be signalled.
+#[tokio::test]
+async fn signal_forwarding_helper() {
+    if std::env::var("ZQUTILS_FORWARDING_HELPER").is_err() {
+        return;
+    }
+    registry::set_grace(Duration::from_millis(300));
+    registry::forward_signals(true);
+    let polite = CommandBuilder::new()
+        .cmd("sleep", &["30"])
+        .spawn()
+        .await
+        .expect("Cannot spawn");
+    let stubborn = CommandBuilder::new()
+        .cmd("sh", &["-c", "trap '' TERM; echo trapped; sleep 30"])
+        .create_new_session()
+        .spawn_logged()
+        .await
+        .expect("Cannot spawn");
+    stubborn
+        .wait_for_line("^trapped$", Duration::from_secs(5))
+        .await
+        .expect("Never trapped SIGTERM");
+    println!(
+        "pids {0} {1}",
+        polite.child.id().unwrap(),
+        stubborn.child.id().unwrap()
+    );
+    tokio::time::sleep(Duration::from_secs(30)).await;
+}
+
+#[tokio::test]
+async fn test_signal_forwarding() {
+    use std::io::BufRead as _;
+    let mut helper = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "signal_forwarding_helper", "--nocapture"])
+        .env("ZQUTILS_FORWARDING_HELPER", "1")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("Cannot start helper");
+    // Keep reading its output, so that it can write to it while stopping.
+    let mut lines = std::io::BufReader::new(helper.stdout.take().unwrap()).lines();
+    let pids: Vec<i32> = lines
+        .by_ref()
+        .map_while(|x| x.ok())
+        .find_map(|x| {
+            x.strip_prefix("pids ")
+                .map(|x| x.split(' ').map(|x| x.parse().unwrap()).collect())
+        })
+        .expect("Helper didn't start its children");
+    let started = std::time::Instant::now();
+    zqutils::process::kill(i32::try_from(helper.id()).unwrap(), libc::SIGTERM);
+    let output: Vec<String> = lines.map_while(|x| x.ok()).collect();
+    let status = helper.wait().expect("Cannot wait for helper");
+    assert!(
+        output.iter().any(|x| x.contains("Caught signal")),
+        "{output:?}"
+    );
+    assert_eq!(status.code(), Some(128 + libc::SIGTERM));
+    // Our children had the grace period to exit before the stubborn one was killed.
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    for _ in 0..50 {
+        if pids.iter().all(|x| zqutils::process::kill(*x, 0) != 0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for pid in pids {
+        assert_ne!(zqutils::process::kill(pid, 0), 0, "{pid} is still running");
+    }
+}
+
+#[tokio::test]
+async fn test_registry() {
+    let is_tracked = |pid| registry::tracked().iter().any(|x| x.pid == pid);
+    let mut tracked = CommandBuilder::new()
+        .cmd("sleep", &["5"])
+        .create_new_session()
+        .spawn()
+        .await
+        .expect("Cannot spawn");
+    let pid = tracked.child.id().unwrap();
+    let entry = registry::tracked()
+        .into_iter()
+        .find(|x| x.pid == pid)
+        .expect("Child not tracked");
+    assert!(entry.session_leader);
+    assert_eq!(entry.description, "sleep 5");
+    let mut detached = CommandBuilder::new()
+        .cmd("sleep", &["5"])
+        .detached()
+        .spawn()
+        .await
+        .expect("Cannot spawn");
+    assert!(!is_tracked(detached.child.id().unwrap()));
+    tracked
+        .wait_with_timeout(Some(Duration::from_millis(100)), &Default::default())
+        .await
+        .expect("Cannot wait");
+    assert!(!is_tracked(pid));
+    detached.child.kill().await.expect("Cannot kill");
+
+    let mut waited = CommandBuilder::new()
+        .cmd("true", &[])
+        .spawn()
+        .await
+        .expect("Cannot spawn");
+    let pid = waited.child.id().unwrap();
+    assert!(is_tracked(pid));
+    assert!(waited.wait().await.expect("Cannot wait").success());
+    assert!(!is_tracked(pid));
+}
+
+#[tokio::test]
+async fn test_execution_mode() {
+    // A dry run only runs read-only commands, whoever builds them.
+    let fake = Arc::new(FakeRunner::new());
+    fake.respond_any("docker", CommandOutput::fake(true));
+    let runner: Arc<dyn CommandRunner> = fake.clone();
+    mode::with_mode(ExecutionMode::DryRun, async {
+        containers::kill_container_with(&runner, "nonesuch")
+            .await
+            .expect("Cannot kill container");
+        assert!(containers::is_container_running_with(&runner, "nonesuch")
+            .await
+            .expect("Cannot check container"));
+        assert!(CommandBuilder::new()
+            .cmd("true", &[])
+            .spawn()
+            .await
+            .is_err());
+        let result = CommandBuilder::new()
+            .cmd("false", &[])
+            .run()
+            .await
+            .expect("Dry run failed");
+        assert!(result.success);
+    })
+    .await;
+    let calls: Vec<Vec<String>> = fake.calls().into_iter().map(|x| x.args).collect();
+    assert_eq!(
+        calls,
+        vec![vec!["inspect", "-f", "{{.State.Running}}", "nonesuch"]]
+    );
+
+    // Commands run on tasks we spawn are in the mode we were in.
+    let touched = std::env::temp_dir().join(format!("zqutils-touched-{0}", std::process::id()));
+    let touch = CommandBuilder::new()
+        .cmd("touch", &[&touched.display().to_string()])
+        .clone();
+    mode::with_mode(ExecutionMode::DryRun, async {
+        let result = Parallel::new(1)
+            .add("touch", &touch)
+            .run()
+            .await
+            .expect("Parallel dry run failed");
+        assert_eq!(result.summary.succeeded, 1);
+        let mut supervisor = Supervisor::new();
+        supervisor
+            .add(
+                "touch",
+                &touch,
+                RestartPolicy::Never,
+                &LogFile::new(&touched.with_extension("log")),
+            )
+            .expect("Cannot add service");
+        for _ in 0..100 {
+            if supervisor.status("touch") == Some(ServiceStatus::Stopped) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(supervisor.status("touch"), Some(ServiceStatus::Stopped));
+    })
+    .await;
+    assert!(!touched.exists());
+    let _ = std::fs::remove_file(touched.with_extension("log"));
+
+    // Record, then replay.
+    let recording = Recording::new();
+    mode::with_mode(ExecutionMode::Record(recording.clone()), async {
+        CommandBuilder::new()
+            .cmd("echo", &["recorded"])
+            .run_for_output()
+            .await
+            .expect("Error executing command");
+        CommandBuilder::new()
+            .cmd("echo", &["logged"])
+            .run()
+            .await
+            .expect("Error executing command");
+    })
+    .await;
+    assert_eq!(recording.commands().len(), 2);
+    let replay = Arc::new(recording.replay());
+    for word in ["recorded", "logged"] {
+        let result = CommandBuilder::new()
+            .cmd("echo", &[word])
+            .runner(replay.clone())
+            .run_for_output()
+            .await
+            .expect("Cannot replay");
+        assert_eq!(result.sanitise_stdout().unwrap(), word);
+    }
+
+    // A command's own mode beats the current one.
+    let result = CommandBuilder::new()
+        .cmd("false", &[])
+        .dry_run()
+        .run()
+        .await
+        .expect("Dry run failed");
+    assert!(result.success);
+}
+
+#[tokio::test]
+async fn test_manifest() {
+    let yaml = r#"
+name: test
+steps:
+  - packages: [git, curl]
+    when: { os: { ID: ubuntu } }
+  - path: /opt/tool/bin
+    when: { arch: aarch64 }
+  - env: { TOOL_HOME: /opt/tool }
+  - name: Say hello
+    shell: echo hello
+"#;
+    let manifest = Manifest::from_yaml(yaml, "test.yaml").expect("Cannot parse manifest");
+    let fake = Arc::new(FakeRunner::new());
+    // The packages are missing at first, then installed.
+    fake.respond_any("bash", CommandOutput::fake(false))
+        .respond_any("arch", CommandOutput::fake(true))
+        .respond_any("sh", CommandOutput::fake(false))
+        .respond_any("sh", CommandOutput::fake(true));
+    let mut ctx = Context::new_with_runner(false, fake.clone())
+        .await
+        .expect("Cannot create context");
+    ctx.arch = "x86_64".to_string();
+    ctx.os_params = [("ID".to_string(), "\"ubuntu\"".to_string())].into();
+    ctx.package_manager = Some(PackageManager::Apt);
+    let plan = ctx.plan_manifest(&manifest);
+    let skipped: Vec<Option<&str>> = plan.iter().map(|x| x.skip_reason.as_deref()).collect();
+    assert_eq!(
+        skipped,
+        vec![None, Some("arch is x86_64, not aarch64"), None, None]
+    );
+    assert_eq!(plan[3].description, "Say hello");
+
+    // A dry run changes the context, but runs nothing.
+    ctx.apply_manifest(&manifest)
+        .await
+        .expect("Cannot plan manifest");
+    assert_eq!(ctx.vars.get("TOOL_HOME").unwrap(), "/opt/tool");
+    assert!(ctx.append_paths.is_empty());
+    let calls = fake.calls();
+    assert!(calls.iter().any(|x| x.cmd == "sh"));
+    assert!(calls.iter().all(|x| x.cmd != "apt-get" && x.cmd != "bash"));
+
+    // Satisfied steps run nothing but their probes, even for real.
+    ctx.really_execute = true;
+    Manifest::from_yaml("steps:\n  - packages: [git, curl]\n", "installed.yaml")
+        .unwrap()
+        .apply(&mut ctx)
+        .await
+        .expect("Cannot apply manifest");
+    let calls = fake.calls();
+    assert_eq!(calls.iter().filter(|x| x.cmd == "sh").count(), 2);
+    assert!(calls.iter().all(|x| x.cmd != "apt-get" && x.cmd != "bash"));
+
+    // Failures point at the step.
+    let err = Manifest::from_yaml("steps:\n  - shell: exit 1\n", "fail.yaml")
+        .unwrap()
+        .apply(&mut ctx)
+        .await
+        .expect_err("Failing step succeeded");
+    assert!(err
+        .to_string()
+        .starts_with("Manifest fail.yaml, step 1 (shell exit 1) failed"));
+
+    let err = Manifest::from_yaml(
+        "steps:\n  - packages: [-rf]\n  - pakages: [git]\n  - shell: a\n    path: /b\n  - env: { 1X: y }\n",
+        "bad.yaml",
+    )
+    .expect_err("Invalid manifest parsed");
+    let err = err.to_string();
+    assert!(err.starts_with("Invalid manifest bad.yaml:"));
+    assert!(err.contains("step 1: packages -rf - '-rf' is not a package name"));
+    assert!(err.contains("step 2: unknown key pakages"));
+    assert!(err.contains("step 3: more than one action (shell, path)"));
+    assert!(err.contains("step 4: env 1X - '1X' is not a variable name"));
+}
+
+#[tokio::test]
+async fn test_steps() {
+    let fake = Arc::new(FakeRunner::new());
+    fake.respond_any("arch", CommandOutput::fake(true))
+        .respond("check", &["done"], CommandOutput::fake(true))
+        .respond("check", &["todo"], CommandOutput::fake(false))
+        .respond_any("apply", CommandOutput::fake(true))
+        .respond_any("look", CommandOutput::fake(true))
+        .respond_any("broken", CommandOutput::fake(false));
+    let mut ctx = Context::new_with_runner(true, fake.clone())
+        .await
+        .expect("Cannot create context");
+    ctx.add_to_path("/opt/tool/bin");
+    let mut probe = CommandBuilder::new();
+
+    let mut step = script::Command::build("apply", &["one"]).unwrap();
+    step.probe(probe.cmd("check", &["done"]));
+    assert_eq!(
+        ctx.run_step(&mut step).await.unwrap(),
+        StepStatus::Unchanged
+    );
+
+    let mut step = script::Command::build("apply", &["two"]).unwrap();
+    step.probe(probe.cmd("check", &["todo"]));
+    assert_eq!(ctx.run_step(&mut step).await.unwrap(), StepStatus::Changed);
+
+    let mut step = script::Command::build("look", &[]).unwrap();
+    step.interrogative();
+    assert_eq!(
+        ctx.run_step(&mut step).await.unwrap(),
+        StepStatus::Unchanged
+    );
+
+    let mut step = script::Command::build("broken", &[]).unwrap();
+    step.optional();
+    assert!(matches!(
+        ctx.run_step(&mut step).await.unwrap(),
+        StepStatus::Failed(_)
+    ));
+    step.mandatory();
+    assert!(ctx.run_step(&mut step).await.is_err());
+
+    let applied: Vec<Vec<String>> = fake
+        .calls()
+        .into_iter()
+        .filter(|x| x.cmd == "apply")
+        .map(|x| x.args)
+        .collect();
+    assert_eq!(applied, vec![vec!["two".to_string()]]);
+    // Running a step again doesn't add our PATH entries again.
+    for call in fake.calls().iter().filter(|x| x.cmd == "broken") {
+        let path = call.env.get("PATH").expect("No PATH");
+        assert_eq!(path.split(':').filter(|x| *x == "/opt/tool/bin").count(), 1);
+    }
+    assert_eq!(ctx.summary(), "2 unchanged, 1 changed, 0 skipped, 2 failed");
+    assert_eq!(ctx.step_reports()[1].description, "apply two");
+
+    // A step is imperative unless we say otherwise, so a dry run doesn't run it.
+    let marker = std::env::temp_dir().join(format!("zqutils-touched-{0}", std::process::id()));
+    let ctx = Context::new_with_runner(false, runner::system())
+        .await
+        .expect("Cannot create context");
+    let mut step = script::Command::new().unwrap();
+    step.builder().cmd("touch", &[marker.to_str().unwrap()]);
+    assert_eq!(
+        ctx.run_step(&mut step).await.unwrap(),
+        StepStatus::Skipped("dry run".to_string())
+    );
+    assert!(!marker.exists());
+}
+
+#[tokio::test]
+async fn test_packages() {
+    let os = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let detected = [
+        os(&[("ID", "ubuntu"), ("ID_LIKE", "debian")]),
+        os(&[("ID", "\"rocky\""), ("ID_LIKE", "\"rhel centos fedora\"")]),
+        os(&[("ID", "alpine")]),
+        os(&[("ID", "cachyos"), ("ID_LIKE", "arch")]),
+        os(&[("ID", "\"opensuse-tumbleweed\"")]),
+    ]
+    .iter()
+    .map(|x| PackageManager::detect(x).ok())
+    .collect::<Vec<_>>();
+    assert_eq!(
+        detected,
+        vec![
+            Some(PackageManager::Apt),
+            Some(PackageManager::Dnf),
+            Some(PackageManager::Apk),
+            Some(PackageManager::Pacman),
+            Some(PackageManager::Zypper)
+        ]
+    );
+    assert!(PackageManager::detect(&os(&[("ID", "plan9")])).is_err());
+
+    let fake = Arc::new(FakeRunner::new());
+    fake.respond_any("arch", CommandOutput::fake(true))
+        .respond_any("rpm", CommandOutput::fake(false))
+        .respond_any("dnf", CommandOutput::fake(true))
+        .respond(
+            "pacman",
+            &["-Q", "git"],
+            CommandOutput {
+                stdout: b"git 2.47.0-1\n".to_vec(),
+                ..CommandOutput::fake(true)
+            },
+        );
+    let mut ctx = Context::new_with_runner(true, fake.clone())
+        .await
+        .expect("Cannot create context");
+    ctx.package_manager = Some(PackageManager::Dnf);
+    ctx.install_packages(&["build-essential", "git"])
+        .await
+        .expect("Cannot install packages");
+    let calls: Vec<String> = fake
+        .calls()
+        .iter()
+        .filter(|x| x.cmd != "arch")
+        .map(|x| format!("{0} {1}", x.cmd, x.args.join(" ")))
+        .collect();
+    assert_eq!(
+        calls,
+        vec![
+            "rpm -q gcc gcc-c++ make git",
+            "dnf -y install gcc gcc-c++ make git"
+        ]
+    );
+
+    ctx.package_manager = Some(PackageManager::Pacman);
+    assert_eq!(
+        ctx.installed_version("git").await.unwrap().as_deref(),
+        Some("2.47.0-1")
+    );
+    let removed = CommandOutput {
+        stdout: b"deinstall 1.0".to_vec(),
+        ..CommandOutput::fake(true)
+    };
+    assert_eq!(PackageManager::Apt.parse_version("x", &removed), None);
+
+    // Removed but not purged is not installed.
+    let stubs = install_stubs();
+    for (pkgs, installed) in [
+        (vec!["git", "curl"], true),
+        (vec!["git", "gone"], false),
+        (vec!["missing"], false),
+    ] {
+        let pkgs: Vec<String> = pkgs.iter().map(|x| x.to_string()).collect();
+        let result = PackageManager::Apt
+            .installed_probe(&pkgs)
+            .path_prepend(&stubs)
+            .silent()
+            .ignore_failures()
+            .run_for_output()
+            .await
+            .expect("Cannot run probe");
+        assert_eq!(result.success, installed, "{pkgs:?}");
+    }
+}